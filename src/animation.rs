@@ -0,0 +1,101 @@
+//! Keyframe animation: values that change from frame to frame, given at a few key frames
+
+use serde::{Deserialize, Serialize};
+
+use crate::fb::Color;
+
+/// How the values between two keys are found
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Straight from one key to the next, at a constant speed
+    Linear,
+    /// A smooth curve through the keys, made of cubic Bezier curves whose handles
+    /// point along the line between the neighbouring keys
+    Bezier,
+    /// Holds every key until the next one
+    Step,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Interpolation::Linear
+    }
+}
+
+/// Values that can be interpolated between keys
+pub trait Keyable: Clone {
+    /// Weighted sum of the values. The weights add up to 1, but some of them can be negative
+    fn blend(values: &[(f32, &Self)]) -> Self;
+}
+
+impl Keyable for f32 {
+    fn blend(values: &[(f32, &Self)]) -> Self {
+        values.iter().map(|&(weight, value)| weight * value).sum()
+    }
+}
+
+impl Keyable for Color {
+    fn blend(values: &[(f32, &Self)]) -> Self {
+        values
+            .iter()
+            .fold(Color::black(), |acc, &(weight, &value)| acc + value * weight)
+    }
+}
+
+/// Value at `frame` of a track with the given keys, which must be sorted by their frame.
+/// Before the first key and after the last one, the value stays at those keys.
+/// `None` if there are no keys
+pub fn sample<T: Keyable>(
+    keys: &[(f32, T)],
+    interpolation: Interpolation,
+    frame: f32,
+) -> Option<T> {
+    let (first, last) = (keys.first()?, keys.last()?);
+    let next = match keys.iter().position(|(key, _)| *key > frame) {
+        Some(0) => return Some(first.1.clone()),
+        Some(next) => next,
+        None => return Some(last.1.clone()),
+    };
+
+    let (start, end) = (&keys[next - 1], &keys[next]);
+    let t = (frame - start.0) / (end.0 - start.0);
+    Some(match interpolation {
+        Interpolation::Step => start.1.clone(),
+        Interpolation::Linear => T::blend(&[(1.0 - t, &start.1), (t, &end.1)]),
+        Interpolation::Bezier => {
+            // A Catmull-Rom spline. The keys at the ends of the track are their own neighbours
+            let before = &keys[next.saturating_sub(2)].1;
+            let after = &keys[(next + 1).min(keys.len() - 1)].1;
+            let (t2, t3) = (t * t, t * t * t);
+            T::blend(&[
+                (0.5 * (-t + 2.0 * t2 - t3), before),
+                (0.5 * (2.0 - 5.0 * t2 + 3.0 * t3), &start.1),
+                (0.5 * (t + 4.0 * t2 - 3.0 * t3), &end.1),
+                (0.5 * (t3 - t2), after),
+            ])
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sample, Interpolation};
+
+    #[test]
+    fn interpolations() {
+        let keys = [(0.0, 0.0), (10.0, 1.0), (20.0, 3.0)];
+
+        assert_eq!(sample(&keys, Interpolation::Linear, 5.0), Some(0.5));
+        assert_eq!(sample(&keys, Interpolation::Step, 15.0), Some(1.0));
+        assert_eq!(sample(&keys, Interpolation::Linear, -5.0), Some(0.0));
+        assert_eq!(sample(&keys, Interpolation::Bezier, 25.0), Some(3.0));
+
+        // The curve passes through the keys, and bends towards the steeper second segment
+        let at_key = sample(&keys, Interpolation::Bezier, 10.0).unwrap();
+        assert!((at_key - 1.0).abs() < 1e-6);
+        let before_key = sample(&keys, Interpolation::Bezier, 5.0).unwrap();
+        assert!(before_key < 0.5);
+
+        assert_eq!(sample::<f32>(&[], Interpolation::Linear, 0.0), None);
+    }
+}
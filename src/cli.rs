@@ -0,0 +1,468 @@
+use std::{num::NonZeroU32, path::PathBuf};
+
+use clap::{App, Arg};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Ppm,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "png" => Ok(Format::Png),
+            "ppm" => Ok(Format::Ppm),
+            _ => Err(format!(
+                "Unknown output format `{}`, expected `png` or `ppm`",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+/// Values that replace the ones in the scene file
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub size: Option<(u16, u16)>,
+    pub fov: Option<f32>,
+    pub steps: Option<usize>,
+    pub multisample: Option<bool>,
+    pub crop: Option<rtlib::scene::Crop>,
+}
+
+impl Overrides {
+    pub fn apply(&self, scene: &mut rtlib::scene::Scene) {
+        if let Some(size) = self.size {
+            scene.size = size;
+        }
+        if let Some(fov) = self.fov {
+            scene.fov = fov;
+        }
+        if let Some(steps) = self.steps {
+            scene.steps = steps;
+        }
+        if let Some(multisample) = self.multisample {
+            scene.multisample = multisample;
+        }
+        if let Some(crop) = self.crop {
+            scene.crop = Some(crop);
+        }
+    }
+}
+
+pub struct Options {
+    pub scene: PathBuf,
+    pub output: PathBuf,
+    pub format: Format,
+    pub overrides: Overrides,
+    /// Number of progressive passes. Without it every pixel is traced once, or four times with multisampling
+    pub samples: Option<u32>,
+    pub threads: Option<usize>,
+    /// Image the cropped render is inserted into
+    pub insert_into: Option<PathBuf>,
+    pub watch: bool,
+    /// Renders every frame of the animation, to files numbered by `frame_output`
+    pub animate: bool,
+    /// Writes one of the passes instead of the shaded image
+    pub pass: Option<rtlib::inspect::Aov>,
+    pub verbosity: Verbosity,
+}
+
+pub fn parse() -> Result<Options, String> {
+    parse_from(std::env::args_os())
+}
+
+/// Parses the arguments, starting with the name of the program. Exits with a usage message
+/// if they don't fit the arguments of the program
+fn parse_from<I, T>(args: I) -> Result<Options, String>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = App::new("raytrace")
+        .about("Renders RON scene files")
+        .arg(
+            Arg::with_name("SCENE")
+                .help("Path to the scene file")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .default_value("result.png")
+                .help("Where to write the image"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["png", "ppm"])
+                .help("Image format. Guessed from the output extension by default"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .short("s")
+                .long("size")
+                .value_name("WIDTHxHEIGHT")
+                .help("Overrides the image size"),
+        )
+        .arg(
+            Arg::with_name("fov")
+                .long("fov")
+                .value_name("DEGREES")
+                .help("Overrides the field of view"),
+        )
+        .arg(
+            Arg::with_name("steps")
+                .long("steps")
+                .value_name("N")
+                .help("Overrides the maximum number of reflections"),
+        )
+        .arg(
+            Arg::with_name("multisample")
+                .long("multisample")
+                .help("Enables 2x2 multisampling"),
+        )
+        .arg(
+            Arg::with_name("no-multisample")
+                .long("no-multisample")
+                .conflicts_with("multisample")
+                .help("Disables 2x2 multisampling"),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .long("samples")
+                .value_name("N")
+                .conflicts_with_all(&["multisample", "no-multisample"])
+                .help("Refines the image with N jittered samples per pixel"),
+        )
+        .arg(
+            Arg::with_name("crop")
+                .short("c")
+                .long("crop")
+                .value_name("X,Y,WIDTH,HEIGHT")
+                .help("Traces only a part of the image. Values are in pixels, or fractions of the image size if any of them has a decimal point"),
+        )
+        .arg(
+            Arg::with_name("insert-into")
+                .long("insert-into")
+                .value_name("PNG")
+                .requires("crop")
+                .help("Inserts the cropped render into an existing image instead of writing it alone"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("j")
+                .long("threads")
+                .value_name("N")
+                .help("Number of render threads. Defaults to the number of cores"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .short("w")
+                .long("watch")
+                .help("Shows the render in a window and re-renders when the scene file changes"),
+        )
+        .arg(
+            Arg::with_name("animate")
+                .short("a")
+                .long("animate")
+                .conflicts_with("watch")
+                .help("Renders every frame of the animation. `#` in the output path is replaced by the frame number, or the number is added before the extension"),
+        )
+        .arg(
+            Arg::with_name("pass")
+                .long("pass")
+                .value_name("PASS")
+                .possible_values(&["normal", "depth", "diffuse", "object", "occlusion"])
+                .conflicts_with_all(&["samples", "watch"])
+                .help("Writes the normals, depth, diffuse colors, objects or ambient occlusion instead of the shaded image"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Prints nothing but errors"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .conflicts_with("quiet")
+                .help("Prints scene details and timings"),
+        )
+        .get_matches_from(args);
+
+    let output = PathBuf::from(matches.value_of("output").unwrap());
+    let format = match matches.value_of("format") {
+        Some(format) => Format::from_name(format)?,
+        None => match output.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => Format::from_name(ext)?,
+            None => Format::Png,
+        },
+    };
+
+    let multisample = if matches.is_present("multisample") {
+        Some(true)
+    } else if matches.is_present("no-multisample") {
+        Some(false)
+    } else {
+        None
+    };
+
+    let verbosity = if matches.is_present("quiet") {
+        Verbosity::Quiet
+    } else if matches.is_present("verbose") {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+
+    Ok(Options {
+        scene: PathBuf::from(matches.value_of("SCENE").unwrap()),
+        output,
+        format,
+        overrides: Overrides {
+            size: matches.value_of("size").map(parse_size).transpose()?,
+            fov: parse_value(matches.value_of("fov"), "fov")?,
+            steps: parse_value(matches.value_of("steps"), "steps")?,
+            multisample,
+            crop: matches.value_of("crop").map(parse_crop).transpose()?,
+        },
+        // A render needs at least one sample per pixel
+        samples: parse_value(matches.value_of("samples"), "samples")?.map(NonZeroU32::get),
+        threads: parse_value(matches.value_of("threads"), "threads")?,
+        insert_into: matches.value_of("insert-into").map(PathBuf::from),
+        watch: matches.is_present("watch"),
+        animate: matches.is_present("animate"),
+        pass: matches.value_of("pass").map(parse_pass).transpose()?,
+        verbosity,
+    })
+}
+
+impl Options {
+    /// Output path of a frame of the animation. A run of `#` in the file name is replaced by
+    /// the frame number padded to its length, otherwise `_0000` is added before the extension
+    pub fn frame_output(&self, frame: u32) -> PathBuf {
+        let name = self
+            .output
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let name = match name.find('#') {
+            Some(start) => {
+                let digits = name[start..].chars().take_while(|&c| c == '#').count();
+                format!(
+                    "{}{:0width$}{}",
+                    &name[..start],
+                    frame,
+                    &name[start + digits..],
+                    width = digits
+                )
+            }
+            None => match name.rfind('.') {
+                Some(dot) if dot > 0 => format!("{}_{:04}{}", &name[..dot], frame, &name[dot..]),
+                _ => format!("{}_{:04}", name, frame),
+            },
+        };
+        self.output.with_file_name(name)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(value: Option<&str>, name: &str) -> Result<Option<T>, String> {
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value `{}` for --{}", value, name))
+        })
+        .transpose()
+}
+
+fn parse_size(size: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid size `{}`, expected WIDTHxHEIGHT", size);
+
+    let mut parts = size.split('x');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(width), Some(height), None) => {
+            let width = width.trim().parse().map_err(|_| invalid())?;
+            let height = height.trim().parse().map_err(|_| invalid())?;
+            if width == 0 || height == 0 {
+                return Err(invalid());
+            }
+            Ok((width, height))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_pass(pass: &str) -> Result<rtlib::inspect::Aov, String> {
+    use rtlib::inspect::Aov;
+
+    match pass {
+        "normal" => Ok(Aov::Normal),
+        "depth" => Ok(Aov::Depth),
+        "diffuse" => Ok(Aov::Diffuse),
+        "object" => Ok(Aov::Object),
+        "occlusion" => Ok(Aov::Occlusion),
+        _ => Err(format!("Unknown pass `{}`", pass)),
+    }
+}
+
+fn parse_crop(crop: &str) -> Result<rtlib::scene::Crop, String> {
+    use rtlib::scene::Crop;
+
+    let invalid = || format!("Invalid crop window `{}`, expected X,Y,WIDTH,HEIGHT", crop);
+
+    let parts = crop.split(',').map(str::trim).collect::<Vec<_>>();
+    if parts.len() != 4 {
+        return Err(invalid());
+    }
+
+    if parts.iter().any(|part| part.contains('.')) {
+        let values = parts
+            .iter()
+            .map(|part| part.parse::<f32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Crop::Normalized {
+            x: values[0],
+            y: values[1],
+            width: values[2],
+            height: values[3],
+        })
+    } else {
+        let values = parts
+            .iter()
+            .map(|part| part.parse::<u16>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Crop::Pixels {
+            x: values[0],
+            y: values[1],
+            width: values[2],
+            height: values[3],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_crop, parse_from, Format, Options, Overrides, Verbosity};
+    use rtlib::scene::Crop;
+    use std::path::PathBuf;
+
+    #[test]
+    fn numbers_frame_outputs() {
+        let frame = |output: &str, frame| {
+            let options = Options {
+                scene: PathBuf::from("scene.ron"),
+                output: PathBuf::from(output),
+                format: Format::Png,
+                overrides: Overrides::default(),
+                samples: None,
+                threads: None,
+                insert_into: None,
+                watch: false,
+                animate: true,
+                pass: None,
+                verbosity: Verbosity::Normal,
+            };
+            options.frame_output(frame)
+        };
+
+        assert_eq!(frame("out.png", 7), PathBuf::from("out_0007.png"));
+        assert_eq!(frame("renders/out_###.png", 7), PathBuf::from("renders/out_007.png"));
+        // Numbers longer than the run of `#` aren't cut off
+        assert_eq!(frame("out_#.png", 12), PathBuf::from("out_12.png"));
+        assert_eq!(frame("renders/out", 7), PathBuf::from("renders/out_0007"));
+        // The dot of a dotfile doesn't start an extension
+        assert_eq!(frame(".out", 7), PathBuf::from(".out_0007"));
+        assert_eq!(frame("renders/.out.png", 7), PathBuf::from("renders/.out_0007.png"));
+    }
+
+    #[test]
+    fn parses_crop_windows() {
+        assert_eq!(
+            parse_crop("10, 20,30,40"),
+            Ok(Crop::Pixels {
+                x: 10,
+                y: 20,
+                width: 30,
+                height: 40,
+            })
+        );
+        // One decimal point makes all the values fractions
+        assert_eq!(
+            parse_crop("0.25,0,1,0.5"),
+            Ok(Crop::Normalized {
+                x: 0.25,
+                y: 0.0,
+                width: 1.0,
+                height: 0.5,
+            })
+        );
+        // Windows past the edges are clamped later, when the image size is known
+        assert_eq!(
+            parse_crop("-0.5,0.5,2.0,1.0"),
+            Ok(Crop::Normalized {
+                x: -0.5,
+                y: 0.5,
+                width: 2.0,
+                height: 1.0,
+            })
+        );
+
+        for invalid in &["1,2,3", "1,2,3,4,5", "-1,0,10,10", "a,0,10,10", "0.5,0,x,1"] {
+            assert!(parse_crop(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let options = parse_from(["raytrace", "scene.ron"]).unwrap();
+        assert_eq!(options.output, PathBuf::from("result.png"));
+        assert_eq!(options.format, Format::Png);
+        assert_eq!(options.samples, None);
+        assert_eq!(options.verbosity, Verbosity::Normal);
+
+        let options = parse_from([
+            "raytrace", "scene.ron", "-o", "out.ppm", "--size", "320x240", "--fov", "60", "--steps", "3",
+            "--no-multisample", "--crop", "0,0,0.5,0.5", "-j", "2", "-v",
+        ])
+        .unwrap();
+        assert_eq!(options.format, Format::Ppm);
+        assert_eq!(options.overrides.size, Some((320, 240)));
+        assert_eq!(options.overrides.fov, Some(60.0));
+        assert_eq!(options.overrides.steps, Some(3));
+        assert_eq!(options.overrides.multisample, Some(false));
+        assert!(matches!(options.overrides.crop, Some(Crop::Normalized { .. })));
+        assert_eq!(options.threads, Some(2));
+        assert_eq!(options.verbosity, Verbosity::Verbose);
+
+        let args = ["raytrace", "scene.ron", "--samples", "16", "-f", "png", "-o", "out"];
+        let options = parse_from(args).unwrap();
+        assert_eq!(options.samples, Some(16));
+        assert_eq!(options.format, Format::Png);
+
+        let invalid = [
+            &["raytrace", "scene.ron", "--size", "0x240"][..],
+            &["raytrace", "scene.ron", "--samples", "many"],
+            &["raytrace", "scene.ron", "--samples", "0"],
+            &["raytrace", "scene.ron", "-o", "out.jpg"],
+        ];
+        for args in &invalid {
+            assert!(parse_from(*args).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Camera controls of the preview window
+//!
+//! Fly mode: W/S/A/D move, Q/E go down and up, arrows or dragging with the left mouse button look around.
+//! Orbit mode: arrows, A/D or dragging circle around the point in front of the camera, W/S or the
+//! scroll wheel move closer and further.
+//! Shift speeds everything up, Tab switches between the modes. Moving the camera levels the horizon.
+
+use minifb::{Key, MouseButton, MouseMode, Window};
+use na::{Isometry3, Point3, Translation3, UnitQuaternion, Vector3};
+
+const MOVE_SPEED: f32 = 2.0;
+const TURN_SPEED: f32 = 1.5;
+const MOUSE_SENSITIVITY: f32 = 0.005;
+const ZOOM_STEP: f32 = 1.1;
+const FAST_MULTIPLIER: f32 = 4.0;
+const MAX_PITCH: f32 = 1.55;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Fly,
+    Orbit,
+}
+
+pub struct Controls {
+    position: Point3<f32>,
+    yaw: f32,
+    pitch: f32,
+    /// Distance to the point orbited around
+    distance: f32,
+    mode: Mode,
+    mouse: Option<(f32, f32)>,
+    /// Whether the camera was moved since it was last reset
+    moved: bool,
+}
+
+impl Controls {
+    pub fn new(camera: Isometry3<f32>) -> Self {
+        let mut controls = Controls {
+            position: Point3::origin(),
+            yaw: 0.0,
+            pitch: 0.0,
+            distance: 1.0,
+            mode: Mode::Fly,
+            mouse: None,
+            moved: false,
+        };
+        controls.reset(camera);
+        controls
+    }
+
+    /// Puts the camera back to the given position
+    pub fn reset(&mut self, camera: Isometry3<f32>) {
+        let forward = camera.rotation * Vector3::z();
+
+        self.position = Point3::from(camera.translation.vector);
+        self.yaw = forward.x.atan2(forward.z);
+        self.pitch = (-forward.y).max(-1.0).min(1.0).asin();
+        // Orbit around the point in front of the camera that is closest to the origin
+        self.distance = (-self.position.coords).dot(&forward).max(1.0);
+        self.moved = false;
+    }
+
+    pub fn moved(&self) -> bool {
+        self.moved
+    }
+
+    pub fn camera(&self) -> Isometry3<f32> {
+        Isometry3::from_parts(Translation3::from(self.position.coords), self.rotation())
+    }
+
+    fn rotation(&self) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), self.yaw)
+            * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), self.pitch)
+    }
+
+    fn forward(&self) -> Vector3<f32> {
+        self.rotation() * Vector3::z()
+    }
+
+    /// Applies the input of one frame that lasted `dt` seconds. Returns whether the camera moved
+    pub fn update(&mut self, window: &Window, dt: f32) -> bool {
+        if window.is_key_pressed(Key::Tab, minifb::KeyRepeat::No) {
+            self.mode = match self.mode {
+                Mode::Fly => Mode::Orbit,
+                Mode::Orbit => Mode::Fly,
+            };
+        }
+
+        let key = |key| if window.is_key_down(key) { 1.0 } else { 0.0 };
+        let speed = if window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift) {
+            FAST_MULTIPLIER
+        } else {
+            1.0
+        };
+
+        let mut turn = (
+            (key(Key::Right) - key(Key::Left)) * TURN_SPEED * speed * dt,
+            (key(Key::Down) - key(Key::Up)) * TURN_SPEED * speed * dt,
+        );
+        let mouse = window
+            .get_mouse_pos(MouseMode::Discard)
+            .filter(|_| window.get_mouse_down(MouseButton::Left));
+        if let (Some((x, y)), Some((prev_x, prev_y))) = (mouse, self.mouse) {
+            turn.0 += (x - prev_x) * MOUSE_SENSITIVITY;
+            turn.1 += (y - prev_y) * MOUSE_SENSITIVITY;
+        }
+        self.mouse = mouse;
+
+        let scroll = window.get_scroll_wheel().map(|(_, y)| y).unwrap_or(0.0);
+        let forward = key(Key::W) - key(Key::S);
+        let side = key(Key::D) - key(Key::A);
+        let up = key(Key::E) - key(Key::Q);
+
+        if turn == (0.0, 0.0) && forward == 0.0 && side == 0.0 && up == 0.0 && scroll == 0.0 {
+            return false;
+        }
+
+        // Scrolling counts as a tenth of a second of holding W or S
+        let scroll = if scroll == 0.0 {
+            0.0
+        } else {
+            scroll.signum() * 0.1
+        };
+
+        match self.mode {
+            Mode::Fly => {
+                self.turn(turn.0, turn.1);
+
+                let right = self.rotation() * Vector3::x();
+                let movement = self.forward() * (forward * dt + scroll)
+                    + (right * side + Vector3::y() * up) * dt;
+                self.position += movement * MOVE_SPEED * speed;
+            }
+            Mode::Orbit => {
+                let target = self.position + self.forward() * self.distance;
+
+                self.turn(turn.0 + side * TURN_SPEED * speed * dt, turn.1);
+                self.distance *= ZOOM_STEP.powf(-(forward * dt + scroll) * speed * 10.0);
+                self.distance = self.distance.max(0.01);
+                self.position = target - self.forward() * self.distance;
+            }
+        }
+
+        self.moved = true;
+        true
+    }
+
+    fn turn(&mut self, yaw: f32, pitch: f32) {
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).max(-MAX_PITCH).min(MAX_PITCH);
+    }
+}
@@ -0,0 +1,342 @@
+//! Image-based lighting: an equirectangular HDR image of the surroundings of the scene, which is
+//! seen where rays leave the scene and lights the surfaces like a light source in every direction
+
+use std::{f32::consts::PI, path::PathBuf, sync::Arc};
+
+use na::{UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{fb::Color, sampling::Rng};
+
+/// Settings of the environment of the scene
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Environment {
+    /// Radiance `.hdr` image, relative to the scene file. Its middle is straight ahead of the camera
+    /// at its default position, its top row straight up
+    pub image: PathBuf,
+    /// Turns the environment around the vertical axis, in degrees
+    #[serde(default)]
+    pub rotation: f32,
+    /// Scales the light of the environment
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    /// Number of directions that light from the environment is gathered from at every point
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+    /// Filled in when the scene is loaded
+    #[serde(skip)]
+    pub map: Option<Arc<EnvironmentMap>>,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_samples() -> u32 {
+    16
+}
+
+impl Environment {
+    fn rotation(&self) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), self.rotation.to_radians())
+    }
+
+    /// Light arriving from the direction. Black if the image hasn't been read
+    pub fn radiance(&self, dir: &Vector3<f32>) -> Color {
+        match &self.map {
+            Some(map) => map.radiance(&(self.rotation().inverse() * dir)) * self.intensity,
+            None => Color::black(),
+        }
+    }
+
+    /// A direction picked in proportion to the light arriving from it, with that light and the
+    /// probability density of picking it per unit of solid angle
+    pub fn sample(&self, rng: &mut Rng) -> Option<(Vector3<f32>, Color, f32)> {
+        let (dir, radiance, pdf) = self.map.as_ref()?.sample(rng)?;
+        Some((self.rotation() * dir, radiance * self.intensity, pdf))
+    }
+}
+
+/// Pixels of an equirectangular image, with the distributions that pick them by their brightness
+#[derive(Debug)]
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+    /// Sum of the weights of all the pixels, their luminance scaled by the area they cover
+    total: f32,
+    /// Fraction of the total weight in every row and the rows above it
+    rows: Vec<f32>,
+    /// For every row, the fraction of the weight of the row in every pixel and the pixels left of it
+    columns: Vec<f32>,
+}
+
+impl EnvironmentMap {
+    /// `pixels` are given row by row, starting at the top
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        // Rows near the poles cover less of the sphere, so they are picked less often
+        let weights = (0..height)
+            .flat_map(|row| {
+                let sin = (PI * (row as f32 + 0.5) / height as f32).sin();
+                pixels[row * width..(row + 1) * width]
+                    .iter()
+                    .map(move |pixel| luminance(pixel) * sin)
+            })
+            .collect::<Vec<_>>();
+
+        let mut columns = Vec::with_capacity(width * height);
+        let mut row_weights = Vec::with_capacity(height);
+        for row in weights.chunks(width) {
+            let total = row.iter().sum::<f32>();
+            row_weights.push(total);
+            columns.extend(cumulative(row, total));
+        }
+        let total = row_weights.iter().sum::<f32>();
+        let rows = cumulative(&row_weights, total).collect();
+
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            total,
+            rows,
+            columns,
+        }
+    }
+
+    /// Decodes a Radiance HDR image, either uncompressed or run-length encoded
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let mut lines = data.split(|&byte| byte == b'\n');
+        let mut read = 0;
+        let mut next_line = || {
+            let line = lines.next().ok_or("The image ends in its header")?;
+            read += line.len() + 1;
+            Ok::<_, String>(String::from_utf8_lossy(line).into_owned())
+        };
+
+        if !next_line()?.starts_with("#?") {
+            return Err("Not a Radiance HDR image".to_string());
+        }
+        loop {
+            let line = next_line()?;
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+                return Err(format!("Unsupported pixel format `{}`", &line[7..]));
+            }
+        }
+        let resolution = next_line()?;
+        let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+            ["-Y", height, "+X", width] => (height.parse::<usize>(), width.parse::<usize>()),
+            _ => return Err(format!("Unsupported image orientation `{}`", resolution)),
+        };
+        let (height, width) = match (height, width) {
+            (Ok(height), Ok(width)) if height > 0 && width > 0 => (height, width),
+            _ => return Err(format!("Invalid image size `{}`", resolution)),
+        };
+
+        let mut data = data.get(read..).unwrap_or_default();
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut scanline = vec![[0; 4]; width];
+        for _ in 0..height {
+            data = read_scanline(data, &mut scanline)?;
+            pixels.extend(scanline.iter().map(|&rgbe| from_rgbe(rgbe)));
+        }
+        Ok(EnvironmentMap::new(width, height, pixels))
+    }
+
+    /// Light arriving from the direction
+    pub fn radiance(&self, dir: &Vector3<f32>) -> Color {
+        let dir = dir.normalize();
+        let u = (dir.x.atan2(-dir.z) / (2.0 * PI)).rem_euclid(1.0);
+        let v = dir.y.max(-1.0).min(1.0).acos() / PI;
+        let column = ((u * self.width as f32) as usize).min(self.width - 1);
+        let row = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[row * self.width + column]
+    }
+
+    /// Picks a pixel in proportion to its weight and a direction within it. `None` if the image is black
+    pub fn sample(&self, rng: &mut Rng) -> Option<(Vector3<f32>, Color, f32)> {
+        if self.total <= 0.0 {
+            return None;
+        }
+        let row = pick(&self.rows, rng.next_f32());
+        let columns = &self.columns[row * self.width..(row + 1) * self.width];
+        let column = pick(columns, rng.next_f32());
+
+        let u = (column as f32 + rng.next_f32()) / self.width as f32;
+        let v = (row as f32 + rng.next_f32()) / self.height as f32;
+        let (phi, theta) = (2.0 * PI * u, PI * v);
+        let dir = Vector3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        );
+
+        // The pixel covers 2 pi^2 sin(theta) / (width * height) of the sphere around this direction
+        let chance = fraction(&self.rows, row) * fraction(columns, column);
+        let area = 2.0 * PI * PI * theta.sin().max(1e-6) / (self.width * self.height) as f32;
+        Some((dir, self.pixels[row * self.width + column], chance / area))
+    }
+}
+
+fn luminance(color: &Color) -> f32 {
+    let [r, g, b] = color.channels();
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Running sums of the weights divided by their total. Evenly spread if the total is 0
+fn cumulative(weights: &[f32], total: f32) -> impl Iterator<Item = f32> + '_ {
+    let count = weights.len() as f32;
+    weights
+        .iter()
+        .enumerate()
+        .scan(0.0, move |sum, (i, &weight)| {
+            *sum += weight;
+            Some(if total > 0.0 {
+                *sum / total
+            } else {
+                (i + 1) as f32 / count
+            })
+        })
+}
+
+/// Position of the first entry of the running sums above `x`
+fn pick(cumulative: &[f32], x: f32) -> usize {
+    cumulative
+        .partition_point(|&sum| sum <= x)
+        .min(cumulative.len() - 1)
+}
+
+/// Part of the running sums that falls on the entry
+fn fraction(cumulative: &[f32], i: usize) -> f32 {
+    cumulative[i] - if i > 0 { cumulative[i - 1] } else { 0.0 }
+}
+
+/// Reads a row of pixels, returning the rest of the data
+fn read_scanline<'a>(data: &'a [u8], scanline: &mut [[u8; 4]]) -> Result<&'a [u8], String> {
+    let width = scanline.len();
+    let ends = || "The image ends before its last pixel".to_string();
+
+    // Run-length encoded rows start with two 2s and the width, and store the channels one by one
+    let encoded = (8..0x8000).contains(&width)
+        && data.len() >= 4
+        && data[0] == 2
+        && data[1] == 2
+        && usize::from(data[2]) << 8 | usize::from(data[3]) == width;
+    if !encoded {
+        let bytes = data.get(..width * 4).ok_or_else(ends)?;
+        for (pixel, rgbe) in scanline.iter_mut().zip(bytes.chunks(4)) {
+            pixel.copy_from_slice(rgbe);
+        }
+        return Ok(&data[width * 4..]);
+    }
+
+    let mut data = &data[4..];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let (&count, rest) = data.split_first().ok_or_else(ends)?;
+            let (run, values, rest) = if count > 128 {
+                let count = usize::from(count - 128);
+                (count, rest.get(..1).ok_or_else(ends)?, &rest[1..])
+            } else {
+                let count = usize::from(count);
+                (
+                    count,
+                    rest.get(..count).ok_or_else(ends)?,
+                    rest.get(count..).ok_or_else(ends)?,
+                )
+            };
+            if run == 0 || x + run > width {
+                return Err("Invalid run length in the image".to_string());
+            }
+            for (i, pixel) in scanline[x..x + run].iter_mut().enumerate() {
+                pixel[channel] = values[if values.len() == 1 { 0 } else { i }];
+            }
+            x += run;
+            data = rest;
+        }
+    }
+    Ok(data)
+}
+
+/// Color of a pixel with a shared exponent
+fn from_rgbe([r, g, b, e]: [u8; 4]) -> Color {
+    if e == 0 {
+        return Color::black();
+    }
+    let scale = 2.0_f32.powi(i32::from(e) - 136);
+    Color::new(
+        f32::from(r) * scale,
+        f32::from(g) * scale,
+        f32::from(b) * scale,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::EnvironmentMap;
+    use crate::{fb::Color, sampling::Rng};
+    use std::f32::consts::PI;
+
+    #[test]
+    fn decodes_run_length_encoded_rows() {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        // Red, green and blue are each a run of 8 pixels, the exponents are written out
+        data.extend_from_slice(&[128 + 8, 128, 128 + 8, 64, 128 + 8, 0]);
+        data.extend_from_slice(&[8, 129, 129, 129, 129, 130, 130, 130, 130]);
+
+        let map = EnvironmentMap::decode(&data).unwrap();
+        assert_eq!((map.width, map.height), (8, 1));
+        assert_eq!(map.pixels[0], Color::new(1.0, 0.5, 0.0));
+        assert_eq!(map.pixels[7], Color::new(2.0, 1.0, 0.0));
+        assert!(EnvironmentMap::decode(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn samples_are_weighted_by_their_density() {
+        // A bright spot on a dim sky
+        let (width, height) = (16, 8);
+        let pixels = (0..width * height)
+            .map(|i| {
+                if i == 2 * width + 5 {
+                    Color::white() * 50.0
+                } else {
+                    Color::white() * 0.1
+                }
+            })
+            .collect::<Vec<_>>();
+        let map = EnvironmentMap::new(width, height, pixels.clone());
+
+        // The light arriving from all directions together, summed over the pixels
+        let expected = (0..width * height)
+            .map(|i| {
+                let row = (i / width) as f32;
+                let band =
+                    (PI * row / height as f32).cos() - (PI * (row + 1.0) / height as f32).cos();
+                pixels[i].channels()[0] * band * 2.0 * PI / width as f32
+            })
+            .sum::<f32>();
+
+        let mut rng = Rng::new(1);
+        let count = 20_000;
+        let mut estimate = 0.0;
+        for _ in 0..count {
+            let (_, radiance, pdf) = map.sample(&mut rng).unwrap();
+            estimate += radiance.channels()[0] / pdf / count as f32;
+        }
+        assert!(
+            (estimate - expected).abs() < expected * 0.05,
+            "{} != {}",
+            estimate,
+            expected
+        );
+
+        assert!(EnvironmentMap::new(2, 1, vec![Color::black(); 2])
+            .sample(&mut rng)
+            .is_none());
+    }
+}
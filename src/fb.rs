@@ -20,6 +20,12 @@ impl Color {
         Color::new(1.0, 1.0, 1.0)
     }
 
+    /// Inverse of `to_pixel`
+    pub fn from_pixel([r, g, b]: [u8; 3]) -> Self {
+        let channel = |c: u8| (c as f32 + 0.5) / 255.0;
+        Color::new(channel(r), channel(g), channel(b))
+    }
+
     pub fn to_pixel(&self) -> [u8; 3] {
         [
             (self.r.max(0.0).min(1.0) * 255.0) as u8,
@@ -28,6 +34,10 @@ impl Color {
         ]
     }
 
+    pub fn channels(&self) -> [f32; 3] {
+        [self.r, self.g, self.b]
+    }
+
     pub fn map(&self, f: impl Fn(f32) -> f32) -> Self {
         Color {
             r: f(self.r),
@@ -81,6 +91,7 @@ impl std::ops::Add<Self> for Color {
     }
 }
 
+#[derive(Clone)]
 pub struct Fb {
     width: u16,
     height: u16,
@@ -100,7 +111,7 @@ impl Fb {
         Fb {
             width,
             height,
-            data: vec![Color::new(0.0, 0.0, 0.0); (width * height) as usize],
+            data: vec![Color::new(0.0, 0.0, 0.0); width as usize * height as usize],
         }
     }
 
@@ -158,6 +169,30 @@ impl Fb {
     pub fn get(&self, x: u16, y: u16) -> Color {
         self.data[pack(x, y, self.width)]
     }
+
+    pub fn set(&mut self, x: u16, y: u16, color: Color) {
+        self.data[pack(x, y, self.width)] = color;
+    }
+
+    /// Resizes the image without any filtering
+    pub fn scale_nearest(&self, width: u16, height: u16) -> Self {
+        Fb::from_func(width, height, |x, y| {
+            self.get(
+                (x as u32 * self.width as u32 / width as u32) as u16,
+                (y as u32 * self.height as u32 / height as u32) as u16,
+            )
+        })
+    }
+
+    /// Copies `other` into this framebuffer with its top left corner at `(x, y)`.
+    /// Parts that don't fit are discarded
+    pub fn insert(&mut self, x: u16, y: u16, other: &Fb) {
+        for oy in 0..other.height.min(self.height.saturating_sub(y)) {
+            for ox in 0..other.width.min(self.width.saturating_sub(x)) {
+                self.set(x + ox, y + oy, other.get(ox, oy));
+            }
+        }
+    }
 }
 
 fn pack(x: u16, y: u16, width: u16) -> usize {
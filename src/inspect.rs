@@ -0,0 +1,190 @@
+//! Recording what happens to the rays of a single pixel, and views of the scene other than the
+//! shaded image, for finding out why a pixel looks the way it does
+
+use std::fmt;
+
+use na::{Point2, Point3, Vector3};
+use nc::query::Ray;
+
+use crate::{fb::Color, material::Material};
+
+/// A ray and everything that was traced because of it
+#[derive(Debug, Clone)]
+pub struct RayNode {
+    pub ray: Ray<f32>,
+    /// `None` if the ray left the scene and got the ambient color
+    pub hit: Option<Hit>,
+    pub color: Color,
+}
+
+impl RayNode {
+    pub(crate) fn new(ray: Ray<f32>) -> Self {
+        RayNode {
+            ray,
+            hit: None,
+            color: Color::black(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hit {
+    /// Index of the object in the scene
+    pub object: usize,
+    pub material: Material,
+    pub point: Point3<f32>,
+    pub normal: Vector3<f32>,
+    /// Distance from the origin of the ray
+    pub depth: f32,
+    /// Texture coordinates, for shapes that have them
+    pub uv: Option<Point2<f32>>,
+    /// Fraction of the ambient light that reaches the point, if it was estimated
+    pub occlusion: Option<f32>,
+    pub shadow_rays: Vec<ShadowRay>,
+    /// `None` if the ray ran out of steps before the reflection could be traced
+    pub reflection: Option<Box<RayNode>>,
+}
+
+/// A ray from a hit point towards a light
+#[derive(Debug, Clone)]
+pub struct ShadowRay {
+    /// Index of the light in the scene
+    pub light: usize,
+    /// Object that blocks the light and its distance from the hit point
+    pub occluder: Option<(usize, f32)>,
+    /// Light added to the hit point through this ray
+    pub contribution: Color,
+}
+
+/// What the preview shows instead of the shaded image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    /// Surface normals, with the axes mapped to red, green and blue
+    Normal,
+    /// Distance from the camera, bright is near
+    Depth,
+    /// Diffuse color of the material
+    Diffuse,
+    /// A different color for every object
+    Object,
+    /// Ambient occlusion, white where all of the ambient light gets through
+    Occlusion,
+}
+
+impl Aov {
+    /// Color of a pixel whose camera ray hit something
+    pub(crate) fn color(self, hit: &Hit, max_depth: f32) -> Color {
+        match self {
+            Aov::Normal => {
+                let n = hit.normal.normalize();
+                Color::new(n.x, n.y, n.z).map(|c| c * 0.5 + 0.5)
+            }
+            Aov::Depth => Color::white() * (1.0 - hit.depth / max_depth).max(0.0),
+            Aov::Diffuse => hit.material.phong.diffuse,
+            Aov::Object => object_color(hit.object),
+            Aov::Occlusion => Color::white() * hit.occlusion.unwrap_or(1.0),
+        }
+    }
+}
+
+/// An arbitrary but stable color for the object with the given index
+fn object_color(index: usize) -> Color {
+    // Golden ratio steps spread the hues of consecutive indices far apart
+    let hue = (index as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    match hue as u32 {
+        0 => Color::new(1.0, x, 0.0),
+        1 => Color::new(x, 1.0, 0.0),
+        2 => Color::new(0.0, 1.0, x),
+        3 => Color::new(0.0, x, 1.0),
+        4 => Color::new(x, 0.0, 1.0),
+        _ => Color::new(1.0, 0.0, x),
+    }
+}
+
+impl fmt::Display for RayNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl RayNode {
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+        let dir = self.ray.dir.normalize();
+        writeln!(
+            f,
+            "{}Ray from {} towards ({:.3}, {:.3}, {:.3}) -> {}",
+            pad,
+            format_point(&self.ray.origin),
+            dir.x,
+            dir.y,
+            dir.z,
+            format_color(self.color)
+        )?;
+
+        let hit = match &self.hit {
+            Some(hit) => hit,
+            None => return writeln!(f, "{}  Missed everything, ambient color", pad),
+        };
+
+        writeln!(
+            f,
+            "{}  Hit object {} at {}, depth {:.3}",
+            pad,
+            hit.object,
+            format_point(&hit.point),
+            hit.depth
+        )?;
+        writeln!(
+            f,
+            "{}  Normal ({:.3}, {:.3}, {:.3})",
+            pad, hit.normal.x, hit.normal.y, hit.normal.z
+        )?;
+        if let Some(uv) = hit.uv {
+            writeln!(f, "{}  UV ({:.3}, {:.3})", pad, uv.x, uv.y)?;
+        }
+        if let Some(occlusion) = hit.occlusion {
+            writeln!(f, "{}  Ambient occlusion {:.3}", pad, occlusion)?;
+        }
+        writeln!(f, "{}  Material {:?}", pad, hit.material)?;
+
+        for shadow in &hit.shadow_rays {
+            match shadow.occluder {
+                Some((object, distance)) => writeln!(
+                    f,
+                    "{}  Light {} blocked by object {} at distance {:.3}",
+                    pad, shadow.light, object, distance
+                )?,
+                None => writeln!(
+                    f,
+                    "{}  Light {} visible, adds {}",
+                    pad,
+                    shadow.light,
+                    format_color(shadow.contribution)
+                )?,
+            }
+        }
+        if hit.shadow_rays.is_empty() {
+            writeln!(f, "{}  No lights", pad)?;
+        }
+
+        match &hit.reflection {
+            Some(reflection) if hit.material.reflect.part > 0.0 => {
+                writeln!(f, "{}  Reflection ({}):", pad, hit.material.reflect.part)?;
+                reflection.write(f, indent + 2)
+            }
+            Some(_) => Ok(()),
+            None => writeln!(f, "{}  Reflection not traced, out of steps", pad),
+        }
+    }
+}
+
+fn format_point(point: &Point3<f32>) -> String {
+    format!("({:.3}, {:.3}, {:.3})", point.x, point.y, point.z)
+}
+
+fn format_color(color: Color) -> String {
+    let [r, g, b] = color.channels();
+    format!("rgb({:.3}, {:.3}, {:.3})", r, g, b)
+}
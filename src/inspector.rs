@@ -0,0 +1,159 @@
+//! Pixel inspector and debug views of the preview window
+//!
+//! I turns the inspector on and off. While it is on, the object under the mouse cursor is printed
+//! whenever the cursor moves to another pixel, and a right click prints everything traced for the pixel.
+//! 1 to 5 show the normals, depth, diffuse colors, objects and ambient occlusion instead of the render.
+//! Pressing the same key again goes back to the render.
+
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window};
+use na::Isometry3;
+use rtlib::{fb::Fb, inspect::Aov, progress::RenderHandle, raytrace::Renderer, scene::Scene};
+
+pub struct Inspector {
+    enabled: bool,
+    overlay: Option<Aov>,
+    overlay_image: Option<Fb>,
+    /// Debug view rendering in the background. Only one renders at a time
+    overlay_render: Option<(Aov, RenderHandle)>,
+    /// Whether the camera or the scene changed since the debug view started rendering
+    overlay_stale: bool,
+    /// The debug view is rendered smaller by this factor
+    overlay_scale: u16,
+    /// Renderer for the current scene and camera, prepared when it is first needed
+    renderer: Option<Renderer>,
+    hovered: Option<(u16, u16)>,
+    right_down: bool,
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Inspector {
+            enabled: false,
+            overlay: None,
+            overlay_image: None,
+            overlay_render: None,
+            overlay_stale: false,
+            overlay_scale: 1,
+            renderer: None,
+            hovered: None,
+            right_down: false,
+        }
+    }
+
+    /// Must be called whenever the scene or the camera changes. The debug view is rendered smaller
+    /// by `scale`, so that it keeps up with a moving camera. The last one is shown until the next is done
+    pub fn invalidate(&mut self, scale: u16) {
+        self.overlay_stale = self.overlay.is_some();
+        self.overlay_scale = scale;
+        self.renderer = None;
+    }
+
+    /// The debug view to show instead of the render, if one is selected
+    pub fn overlay(&self) -> Option<&Fb> {
+        self.overlay_image.as_ref()
+    }
+
+    /// Handles the input of one frame. Returns whether the window needs to be redrawn
+    pub fn update(&mut self, window: &Window, scene: &Scene, camera: Isometry3<f32>) -> bool {
+        let mut redraw = false;
+
+        if window.is_key_pressed(Key::I, KeyRepeat::No) {
+            self.enabled = !self.enabled;
+            self.hovered = None;
+            if self.enabled {
+                println!("Inspector on: hover over a pixel to see what it shows, right click for the whole ray tree");
+            } else {
+                println!("Inspector off");
+            }
+        }
+
+        let views = [
+            (Key::Key1, Aov::Normal),
+            (Key::Key2, Aov::Depth),
+            (Key::Key3, Aov::Diffuse),
+            (Key::Key4, Aov::Object),
+            (Key::Key5, Aov::Occlusion),
+        ];
+        for &(key, aov) in &views {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                self.overlay = if self.overlay == Some(aov) {
+                    None
+                } else {
+                    Some(aov)
+                };
+                self.overlay_image = None;
+                self.overlay_stale = self.overlay.is_some();
+                redraw = true;
+            }
+        }
+
+        let region = scene.region();
+        let finished = self.overlay_render.as_ref().map_or(false, |(_, render)| render.is_finished());
+        if finished {
+            let (aov, render) = self.overlay_render.take().unwrap();
+            if let (Some(image), true) = (render.wait(), self.overlay == Some(aov)) {
+                self.overlay_image = Some(image.scale_nearest(region.width, region.height));
+                redraw = true;
+            }
+        }
+        // An image of the old scene doesn't fit a window that changed its size
+        if let Some(image) = &self.overlay_image {
+            if (image.width(), image.height()) != (region.width, region.height) {
+                self.overlay_image = None;
+                redraw = true;
+            }
+        }
+        if let (Some(aov), true, None) = (self.overlay, self.overlay_stale, &self.overlay_render) {
+            let mut scene = scene.clone();
+            scene.set_camera(camera);
+            scene.downscale(self.overlay_scale);
+            self.overlay_render = Some((aov, rtlib::spawn_pass(scene, aov)));
+            self.overlay_stale = false;
+        }
+
+        let right_down = window.get_mouse_down(MouseButton::Right);
+        let clicked = right_down && !self.right_down;
+        self.right_down = right_down;
+
+        if !self.enabled {
+            return redraw;
+        }
+
+        let pixel = window
+            .get_mouse_pos(MouseMode::Discard)
+            .map(|(x, y)| (region.x + x as u16, region.y + y as u16));
+        let pixel = match pixel {
+            Some(pixel) => pixel,
+            None => return redraw,
+        };
+
+        if clicked {
+            let tree = self
+                .renderer(scene, camera)
+                .inspect(pixel.0 as f32, pixel.1 as f32);
+            print!("Pixel ({}, {}):\n{}", pixel.0, pixel.1, tree);
+        } else if self.hovered != Some(pixel) {
+            let tree = self
+                .renderer(scene, camera)
+                .inspect(pixel.0 as f32, pixel.1 as f32);
+            match &tree.hit {
+                Some(hit) => println!(
+                    "Pixel ({}, {}): object {} at depth {:.3}",
+                    pixel.0, pixel.1, hit.object, hit.depth
+                ),
+                None => println!("Pixel ({}, {}): nothing", pixel.0, pixel.1),
+            }
+        }
+        self.hovered = Some(pixel);
+
+        redraw
+    }
+
+    fn renderer(&mut self, scene: &Scene, camera: Isometry3<f32>) -> &Renderer {
+        self.renderer.get_or_insert_with(|| {
+            let mut scene = scene.clone();
+            scene.set_camera(camera);
+            rtlib::prepare_scene(scene)
+        })
+    }
+}
@@ -3,49 +3,472 @@ extern crate ncollide3d as nc;
 
 extern crate rtlib;
 
+mod cli;
+#[cfg(feature = "update")]
+mod controls;
+#[cfg(feature = "update")]
+mod inspector;
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use cli::{Format, Options, Verbosity};
+
+#[cfg(not(feature = "wasm"))]
+const TILE_SIZE: u16 = 32;
+
 fn main() {
-    let path = std::env::args().nth(1).expect("Expected path to scene");
-
-    #[cfg(feature = "update")]
-    {
-        let mut window: Option<minifb::Window> = None;
-        let mut size = (0, 0);
-        let mut prev_src = None;
-
-        while window.as_ref().map(|w| w.is_open()).unwrap_or(true) {
-            let src = std::fs::read_to_string(&path).unwrap();
-            let scene = match ron::de::from_str::<rtlib::scene::Scene>(&src) {
-                Ok(scene) => scene,
+    if let Err(e) = cli::parse().and_then(|options| run(&options)) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    if let Some(threads) = options.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| format!("Could not start {} threads: {}", threads, e))?;
+    }
+
+    if options.watch {
+        watch(options)
+    } else {
+        render(options)
+    }
+}
+
+fn load_scene(options: &Options) -> Result<rtlib::scene::Scene, String> {
+    let mut scene = rtlib::scene::Scene::load(&options.scene)?;
+
+    options.overrides.apply(&mut scene);
+    Ok(scene)
+}
+
+fn render(options: &Options) -> Result<(), String> {
+    let started = Instant::now();
+    let scene = load_scene(options)?;
+    let loaded = started.elapsed();
+
+    let (width, height) = scene.size;
+    let region = scene.region();
+    if options.verbosity == Verbosity::Verbose {
+        eprintln!(
+            "{}x{}, fov {}, {} steps, multisample: {}",
+            width, height, scene.fov, scene.steps, scene.multisample
+        );
+        if scene.crop.is_some() {
+            eprintln!(
+                "Cropped to {}x{} at ({}, {})",
+                region.width, region.height, region.x, region.y
+            );
+        }
+        eprintln!("Loading:   {:.3}s", loaded.as_secs_f32());
+    }
+
+    let target = match &options.insert_into {
+        Some(path) => {
+            let target = std::fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|png| rtlib::decode(&png))
+                .map_err(|e| format!("Could not load {}: {}", path.display(), e))?;
+            if (target.width(), target.height()) != scene.size {
+                return Err(format!(
+                    "{} is {}x{}, but the scene is {}x{}",
+                    path.display(),
+                    target.width(),
+                    target.height(),
+                    width,
+                    height
+                ));
+            }
+            Some(target)
+        }
+        None => None,
+    };
+
+    if !options.animate {
+        return render_image(options, scene.frame(0), target, &options.output);
+    }
+
+    // The scene is only loaded once, every frame is a copy with the tracks applied
+    let frames = scene.frames();
+    for frame in 0..frames {
+        let output = options.frame_output(frame);
+        if options.verbosity != Verbosity::Quiet {
+            eprintln!("Frame {}/{}", frame + 1, frames);
+        }
+        render_image(options, scene.frame(frame), target.clone(), &output)?;
+    }
+    if options.verbosity != Verbosity::Quiet {
+        eprintln!(
+            "Rendered {} frames in {:.2}s",
+            frames,
+            started.elapsed().as_secs_f32()
+        );
+    }
+
+    Ok(())
+}
+
+/// Renders one image of the scene and writes it to `output`. The render is inserted into `target`, if there is one
+fn render_image(
+    options: &Options,
+    scene: rtlib::scene::Scene,
+    target: Option<rtlib::fb::Fb>,
+    output: &Path,
+) -> Result<(), String> {
+    let started = Instant::now();
+    let region = scene.region();
+    // Multisampling traces every pixel 2x2 times, except for passes and progressive refinement
+    let samples = match (options.pass, options.samples) {
+        (Some(_), _) => 1,
+        (None, Some(samples)) => samples,
+        (None, None) if scene.multisample => 4,
+        (None, None) => 1,
+    };
+
+    let (mut fb, rendered) = trace_image(options, scene)?;
+    if let Some(mut target) = target {
+        target.insert(region.x, region.y, &fb);
+        fb = target;
+    }
+
+    let encoding_started = Instant::now();
+    let result = match options.format {
+        Format::Png => {
+            rtlib::encode(fb).map_err(|e| format!("Could not encode the image: {}", e))?
+        }
+        Format::Ppm => rtlib::encode_ppm(fb),
+    };
+    std::fs::write(output, result)
+        .map_err(|e| format!("Could not write {}: {}", output.display(), e))?;
+    let encoded = encoding_started.elapsed();
+
+    match options.verbosity {
+        Verbosity::Quiet => {}
+        Verbosity::Normal => eprintln!(
+            "Rendered {} in {:.2}s",
+            output.display(),
+            started.elapsed().as_secs_f32()
+        ),
+        Verbosity::Verbose => {
+            let pixels = region.area() as f32 * samples as f32;
+            eprintln!(
+                "Rendering: {:.3}s ({:.0} samples/s)",
+                rendered.as_secs_f32(),
+                pixels / rendered.as_secs_f32()
+            );
+            eprintln!("Encoding:  {:.3}s", encoded.as_secs_f32());
+            eprintln!("Total:     {:.3}s", started.elapsed().as_secs_f32());
+        }
+    }
+
+    Ok(())
+}
+
+/// Traces the image on a background thread while printing its progress, and returns it with the time it took
+#[cfg(not(feature = "wasm"))]
+fn trace_image(options: &Options, scene: rtlib::scene::Scene) -> Result<(rtlib::fb::Fb, Duration), String> {
+    let render = match (options.pass, options.samples) {
+        (Some(pass), _) => rtlib::spawn_pass(scene, pass),
+        (None, Some(samples)) => rtlib::spawn_progressive(scene, samples, None),
+        (None, None) => rtlib::spawn_render(scene, TILE_SIZE),
+    };
+    while !render.is_finished() {
+        if options.verbosity != Verbosity::Quiet {
+            print_progress(render.progress());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    if options.verbosity != Verbosity::Quiet {
+        print_progress(render.progress());
+        eprintln!();
+    }
+    let rendered = render.progress().elapsed();
+
+    let fb = render.wait().ok_or("Render was cancelled")?;
+    Ok((fb, rendered))
+}
+
+/// Traces the image on this thread, because wasm builds can't spawn background renders
+#[cfg(feature = "wasm")]
+fn trace_image(options: &Options, scene: rtlib::scene::Scene) -> Result<(rtlib::fb::Fb, Duration), String> {
+    let started = Instant::now();
+    let region = scene.region();
+
+    let fb = match (options.pass, options.samples) {
+        (Some(pass), _) => rtlib::prepare_scene(scene).render_aov(region, pass),
+        (None, Some(samples)) => {
+            let mut progressive = rtlib::progressive::Progressive::new(scene);
+            while progressive.passes() < samples {
+                progressive.render_pass();
+            }
+            progressive.image()
+        }
+        (None, None) => rtlib::trace_scene(scene),
+    };
+    Ok((fb, started.elapsed()))
+}
+
+#[cfg(not(feature = "wasm"))]
+fn print_progress(progress: &rtlib::progress::Progress) {
+    const WIDTH: usize = 40;
+
+    let filled = ((progress.fraction() * WIDTH as f32) as usize).min(WIDTH);
+    let eta = progress
+        .eta()
+        .map(|eta| format!("{}s left", eta.as_secs()))
+        .unwrap_or_default();
+
+    eprint!(
+        "\r[{}{}] {:>3}% {:<12}",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        (progress.fraction() * 100.0) as u32,
+        eta,
+    );
+}
+
+#[cfg(not(feature = "update"))]
+fn watch(_: &Options) -> Result<(), String> {
+    Err("--watch needs a build with the `update` feature".to_string())
+}
+
+#[cfg(feature = "update")]
+fn watch(options: &Options) -> Result<(), String> {
+    use minifb::{Key, KeyRepeat};
+    use notify::{RecursiveMode, Watcher};
+    use std::{collections::HashSet, path::PathBuf};
+
+    /// Refinement stops after this many samples per pixel
+    const MAX_PASSES: u32 = 64;
+    /// How often the window is refreshed while a pass is being traced
+    const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+    /// Bursts of file events shorter than this cause only one reload
+    const DEBOUNCE: Duration = Duration::from_millis(200);
+    /// Pause between window updates, so the loop doesn't occupy a whole core
+    const FRAME_TIME: Duration = Duration::from_millis(16);
+    /// While the camera moves, the image is traced at this fraction of the resolution
+    const PREVIEW_SCALE: u16 = 4;
+    /// Time without movement after which the full resolution render starts
+    const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+    let passes = options.samples.unwrap_or(MAX_PASSES);
+    let report = options.verbosity != Verbosity::Quiet;
+
+    let absolute = |path: &std::path::Path| {
+        std::env::current_dir()
+            .map(|dir| dir.join(path))
+            .map_err(|e| format!("Could not resolve {}: {}", path.display(), e))
+    };
+
+    let (sender, events) = std::sync::mpsc::channel();
+    let mut watcher =
+        notify::watcher(sender, DEBOUNCE).map_err(|e| format!("Could not watch files: {}", e))?;
+    // Directories are watched rather than files, because many editors save by replacing the file
+    let mut watched_dirs = HashSet::new();
+    let mut watched_files = HashSet::new();
+    let mut watch_file =
+        |path: PathBuf, watched_files: &mut HashSet<PathBuf>| -> Result<(), String> {
+            let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+            if watched_dirs.insert(dir.clone()) {
+                watcher
+                    .watch(&dir, RecursiveMode::NonRecursive)
+                    .map_err(|e| format!("Could not watch {}: {}", dir.display(), e))?;
+            }
+            watched_files.insert(path);
+            Ok(())
+        };
+    watch_file(absolute(&options.scene)?, &mut watched_files)?;
+
+    let start_render = |scene: &rtlib::scene::Scene, camera, preview| {
+        let mut scene = scene.clone();
+        scene.set_camera(camera);
+        if preview {
+            scene.downscale(PREVIEW_SCALE);
+            rtlib::spawn_progressive(scene, 1, Some(REFRESH_INTERVAL))
+        } else {
+            rtlib::spawn_progressive(scene, passes, Some(REFRESH_INTERVAL))
+        }
+    };
+
+    if report {
+        println!("Camera: W/S/A/D/Q/E move, arrows or left mouse button turn, scroll zooms, Shift is faster,");
+        println!("        Tab switches between fly and orbit, R resets, P saves the camera to the scene file");
+        println!("Debug: I inspects pixels, 1/2/3/4/5 show normals, depth, diffuse colors, objects and ambient occlusion");
+    }
+
+    let mut window: Option<minifb::Window> = None;
+    let mut size = (0, 0);
+    let mut scene: Option<rtlib::scene::Scene> = None;
+    let mut controls: Option<controls::Controls> = None;
+    let mut inspector = inspector::Inspector::new();
+    let mut image: Option<rtlib::fb::Fb> = None;
+    let mut render: Option<rtlib::progress::RenderHandle> = None;
+    let mut preview = false;
+    let mut last_move = Instant::now();
+    let mut last_frame = Instant::now();
+    let mut reported_passes = 0;
+    let mut reload = true;
+    let mut last_error = None;
+
+    while window.as_ref().map(|w| w.is_open()).unwrap_or(true) {
+        if reload {
+            reload = false;
+
+            // Animated scenes are shown at their first frame
+            match load_scene(options).map(|scene| scene.frame(0)) {
+                Ok(loaded) => {
+                    last_error = None;
+                    for dependency in loaded.dependencies() {
+                        watch_file(absolute(&dependency)?, &mut watched_files)?;
+                    }
+
+                    let region = loaded.region();
+                    if (region.width, region.height) != size || window.is_none() {
+                        size = (region.width, region.height);
+                        window = Some(
+                            minifb::Window::new(
+                                "Raytrace",
+                                size.0 as usize,
+                                size.1 as usize,
+                                Default::default(),
+                            )
+                            .map_err(|e| format!("Could not open a window: {}", e))?,
+                        );
+                    }
+
+                    // A camera moved by hand stays where it is when the scene changes
+                    let camera = match controls.as_ref().filter(|controls| controls.moved()) {
+                        Some(controls) => controls.camera(),
+                        None => {
+                            controls = Some(controls::Controls::new(loaded.camera()));
+                            loaded.camera()
+                        }
+                    };
+
+                    // Dropping the previous handle cancels the render that is still running
+                    render = Some(start_render(&loaded, camera, false));
+                    preview = false;
+                    inspector.invalidate(1);
+                    scene = Some(loaded);
+                    reported_passes = 0;
+                    if report {
+                        println!("Update!");
+                    }
+                }
+                // The window keeps showing the last good image, and the error is only shown once
                 Err(e) => {
-                    eprintln!("Error: {}", e);
-                    continue;
+                    if last_error.as_ref() != Some(&e) {
+                        eprintln!("Error: {}", e);
+                        last_error = Some(e);
+                    }
+
+                    // There is nothing to show before the scene has been loaded at least once
+                    if window.is_none() {
+                        match events.recv() {
+                            Ok(event) => reload = is_change_of(&event, &watched_files),
+                            Err(_) => return Err("File watcher stopped".to_string()),
+                        }
+                        continue;
+                    }
                 }
-            };
-
-            if Some(src.as_str()) != prev_src.as_ref().map(String::as_str) {
-                if scene.size != size || window.is_none() {
-                    size = scene.size;
-                    window = Some(minifb::Window::new(
-                        "Raytrace",
-                        size.0 as usize,
-                        size.1 as usize,
-                        Default::default(),
-                    ).unwrap());
+            }
+        }
+
+        for event in events.try_iter() {
+            reload |= is_change_of(&event, &watched_files);
+        }
+
+        let window = window.as_mut().unwrap();
+        let mut redraw = false;
+        if let Some(latest) = render.as_ref().and_then(|render| render.latest_image()) {
+            image = Some(if (latest.width(), latest.height()) == size {
+                latest
+            } else {
+                latest.scale_nearest(size.0, size.1)
+            });
+            redraw = true;
+        }
+
+        let dt = last_frame.elapsed().as_secs_f32();
+        last_frame = Instant::now();
+        if let (Some(controls), Some(scene)) = (controls.as_mut(), scene.as_ref()) {
+            if window.is_key_pressed(Key::R, KeyRepeat::No) {
+                controls.reset(scene.camera());
+                render = Some(start_render(scene, controls.camera(), false));
+                preview = false;
+                reported_passes = 0;
+                inspector.invalidate(1);
+            } else if controls.update(window, dt) {
+                render = Some(start_render(scene, controls.camera(), true));
+                preview = true;
+                last_move = Instant::now();
+                inspector.invalidate(PREVIEW_SCALE);
+            } else if preview && last_move.elapsed() > SETTLE_TIME {
+                render = Some(start_render(scene, controls.camera(), false));
+                preview = false;
+                reported_passes = 0;
+                inspector.invalidate(1);
+            }
+
+            redraw |= inspector.update(window, scene, controls.camera());
+
+            if window.is_key_pressed(Key::P, KeyRepeat::No) {
+                let saved = std::fs::read_to_string(&options.scene)
+                    .map_err(|e| e.to_string())
+                    .and_then(|src| rtlib::scene::replace_camera(&src, controls.camera()))
+                    .and_then(|src| std::fs::write(&options.scene, src).map_err(|e| e.to_string()));
+                match saved {
+                    Ok(()) if report => println!("Camera saved to {}", options.scene.display()),
+                    Ok(()) => {}
+                    Err(e) => eprintln!("Error: Could not save the camera: {}", e),
                 }
+            }
+        }
 
-                let window = window.as_mut().unwrap();
-                let buf = rtlib::trace_scene(scene).to_packed_bgr();
-                window.update_with_buffer(&buf).unwrap();
-                println!("Update!");
+        // The debug view replaces the render until it is turned off
+        match inspector.overlay().or(image.as_ref()) {
+            Some(shown) if redraw => window
+                .update_with_buffer(&shown.to_packed_bgr())
+                .map_err(|e| format!("Could not update the window: {}", e))?,
+            _ => window.update(),
+        }
+
+        if let (Some(render), false, true) = (render.as_ref(), preview, report) {
+            let pixels = size.0 as usize * size.1 as usize;
+            let passes = render.progress().pixels_done() / pixels.max(1);
+            if passes > reported_passes {
+                reported_passes = passes;
+                println!(
+                    "{} samples per pixel ({:.2}s)",
+                    passes,
+                    render.progress().elapsed().as_secs_f32()
+                );
             }
-            prev_src = Some(src);
         }
+
+        std::thread::sleep(FRAME_TIME);
     }
-    #[cfg(not(feature = "update"))]
-    {
-        let src = std::fs::read_to_string(path).unwrap();
-        let result = rtlib::trace(&src).unwrap();
 
-        std::fs::write("result.png", result).unwrap();
+    Ok(())
+}
+
+#[cfg(feature = "update")]
+fn is_change_of(
+    event: &notify::DebouncedEvent,
+    files: &std::collections::HashSet<std::path::PathBuf>,
+) -> bool {
+    use notify::DebouncedEvent::*;
+
+    match event {
+        Create(path) | Write(path) | Remove(path) => files.contains(path),
+        Rename(from, to) => files.contains(from) || files.contains(to),
+        Rescan => true,
+        NoticeWrite(_) | NoticeRemove(_) | Chmod(_) | Error(..) => false,
     }
 }
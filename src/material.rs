@@ -1,5 +1,8 @@
+use std::{path::PathBuf, sync::Arc};
+
+use na::Point2;
 use serde::{Serialize, Deserialize};
-use crate::fb::Color;
+use crate::{fb::Color, media::Volume};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Material {
@@ -7,6 +10,46 @@ pub struct Material {
     pub phong: Phong,
     #[serde(default)]
     pub reflect: Reflect,
+    #[serde(default)]
+    pub subsurface: Subsurface,
+    /// Makes the object a volume of fog or smoke. Its surface isn't drawn, rays pass into it instead
+    #[serde(default)]
+    pub volume: Option<Volume>,
+    #[serde(default)]
+    pub transparency: Transparency,
+    /// Cuts holes into the surface, where rays pass through it untouched
+    #[serde(default)]
+    pub mask: Option<Mask>,
+}
+
+impl Material {
+    /// Makes sure that the volume, if there is one, is valid
+    pub fn check(&self) -> Result<(), String> {
+        match &self.volume {
+            Some(volume) => volume.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the surface stops all light
+    pub fn is_opaque(&self) -> bool {
+        self.transparency.part <= 0.0 && self.mask.is_none()
+    }
+
+    /// Part of the surface at the texture coordinates that is there, rather than a hole of the mask
+    pub fn coverage(&self, uv: Option<Point2<f32>>) -> f32 {
+        match (&self.mask, uv) {
+            (Some(mask), Some(uv)) => mask.alpha_at(uv),
+            _ => 1.0,
+        }
+    }
+
+    /// Fraction of the light that passes through the surface at the texture coordinates,
+    /// through the holes of the mask and tinted through the transparent rest
+    pub fn transmission(&self, uv: Option<Point2<f32>>) -> Color {
+        let coverage = self.coverage(uv);
+        Color::white() * (1.0 - coverage) + self.transparency.color * (coverage * self.transparency.part)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,6 +84,77 @@ pub struct Reflect {
     pub part: f32,
 }
 
+/// Light that enters the surface, scatters inside of the object and comes out somewhere else,
+/// like in wax, skin or marble
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subsurface {
+    #[serde(default)]
+    pub part: f32,
+    /// Light takes on this color every time it scatters
+    #[serde(default = "Color::white")]
+    pub color: Color,
+    /// Average distance light travels inside of the object before it scatters
+    #[serde(default = "default_subsurface_radius")]
+    pub radius: f32,
+}
+
+impl Default for Subsurface {
+    fn default() -> Self {
+        Subsurface {
+            part: 0.0,
+            color: Color::white(),
+            radius: default_subsurface_radius(),
+        }
+    }
+}
+
+/// Light that passes straight through the object, like through thin glass or colored foil
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transparency {
+    #[serde(default)]
+    pub part: f32,
+    /// Tints the light that passes through, and the shadow of the object
+    #[serde(default = "Color::white")]
+    pub color: Color,
+}
+
+impl Default for Transparency {
+    fn default() -> Self {
+        Transparency {
+            part: 0.0,
+            color: Color::white(),
+        }
+    }
+}
+
+/// Alpha channel of a PNG, or its brightness if it has none, mapped onto the texture coordinates
+/// of the shape. Shapes without texture coordinates ignore it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mask {
+    /// Relative to the file the material is defined in
+    pub image: PathBuf,
+    /// One row per row of pixels, filled in when the scene is loaded
+    #[serde(skip)]
+    pub alpha: Option<Arc<na::DMatrix<f32>>>,
+}
+
+impl Mask {
+    /// Alpha of the pixel under the texture coordinates, which go from 0 to 1 across the image
+    fn alpha_at(&self, uv: Point2<f32>) -> f32 {
+        let alpha = match &self.alpha {
+            Some(alpha) => alpha,
+            None => return 1.0,
+        };
+        let pixel = |t: f32, size: usize| ((t * size as f32) as usize).min(size - 1);
+        let (u, v) = (uv.x.max(0.0), uv.y.max(0.0));
+        alpha[(pixel(v, alpha.nrows()), pixel(u, alpha.ncols()))]
+    }
+}
+
+fn default_subsurface_radius() -> f32 {
+    0.1
+}
+
 fn default_phong_part() -> f32 {
     1.0
 }
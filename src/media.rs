@@ -0,0 +1,151 @@
+//! Participating media: fog and the insides of volume objects, which take light out of the rays
+//! that pass through them and scatter light from the light sources towards the viewer
+
+use na::Point3;
+use serde::{Deserialize, Serialize};
+
+use crate::fb::Color;
+
+/// Fog filling the whole scene
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Fog {
+    /// Fraction of the light taken out of a ray per unit of distance, at height 0
+    pub density: f32,
+    /// Tints the light that the fog scatters. White fog scatters all the light it takes out of rays,
+    /// black fog absorbs it
+    #[serde(default = "Color::white")]
+    pub color: Color,
+    /// The density falls off exponentially with height at this rate. 0 fills the scene evenly
+    #[serde(default)]
+    pub falloff: f32,
+}
+
+impl Fog {
+    /// Makes sure that the fog takes light out of rays instead of adding to it
+    pub fn check(&self) -> Result<(), String> {
+        if self.density < 0.0 || self.falloff < 0.0 {
+            return Err(format!(
+                "Fog density and falloff can't be negative, but they are {} and {}",
+                self.density, self.falloff
+            ));
+        }
+        Ok(())
+    }
+
+    fn density_at(&self, p: Point3<f32>) -> f32 {
+        self.density * (-self.falloff * p.y).exp()
+    }
+
+    /// Integral of the density along the line between the points
+    fn optical_depth(&self, from: Point3<f32>, to: Point3<f32>) -> f32 {
+        let rise = self.falloff * (to.y - from.y);
+        // Average of exp(-rise * s) for s from 0 to 1
+        let average = if rise.abs() < 1e-4 {
+            1.0 - rise / 2.0
+        } else {
+            -(-rise).exp_m1() / rise
+        };
+        self.density_at(from) * na::distance(&from, &to) * average
+    }
+}
+
+/// Homogeneous medium inside of an object, given per unit of distance
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Volume {
+    /// Fraction of the light that is absorbed
+    #[serde(default = "Color::black")]
+    pub absorption: Color,
+    /// Fraction of the light that is scattered
+    #[serde(default = "Color::black")]
+    pub scattering: Color,
+}
+
+impl Volume {
+    /// Makes sure that the volume takes light out of rays instead of adding to it
+    pub fn check(&self) -> Result<(), String> {
+        let negative = |color: &Color| color.channels().iter().any(|&c| c < 0.0);
+        if negative(&self.absorption) || negative(&self.scattering) {
+            return Err(format!(
+                "Volume absorption and scattering can't be negative, but they are {:?} and {:?}",
+                self.absorption, self.scattering
+            ));
+        }
+        Ok(())
+    }
+
+    fn extinction(&self) -> Color {
+        self.absorption + self.scattering
+    }
+}
+
+/// The medium a ray travels through
+#[derive(Clone, Copy, Debug)]
+pub enum Medium<'a> {
+    Fog(&'a Fog),
+    Volume(&'a Volume),
+}
+
+impl Medium<'_> {
+    /// Light scattered per unit of distance at the point
+    pub fn scattering(&self, p: Point3<f32>) -> Color {
+        match self {
+            Medium::Fog(fog) => fog.color * fog.density_at(p),
+            Medium::Volume(volume) => volume.scattering,
+        }
+    }
+
+    /// Part of the light taken out of rays that is scattered rather than absorbed
+    pub fn albedo(&self) -> Color {
+        match self {
+            Medium::Fog(fog) => fog.color,
+            Medium::Volume(volume) => {
+                volume.scattering.combine(
+                    &volume.extinction(),
+                    |s, e| if e > 0.0 { s / e } else { 0.0 },
+                )
+            }
+        }
+    }
+
+    /// Fraction of the light that gets through in a straight line between the points
+    pub fn transmittance(&self, from: Point3<f32>, to: Point3<f32>) -> Color {
+        match self {
+            Medium::Fog(fog) => Color::white() * (-fog.optical_depth(from, to)).exp(),
+            Medium::Volume(volume) => {
+                let distance = na::distance(&from, &to);
+                volume.extinction().map(|e| (-e * distance).exp())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fog, Medium};
+    use crate::fb::Color;
+    use na::Point3;
+
+    #[test]
+    fn fog_thins_out_with_height() {
+        let fog = Fog {
+            density: 0.5,
+            color: Color::white(),
+            falloff: 1.0,
+        };
+        let medium = Medium::Fog(&fog);
+        let transmittance =
+            |from: Point3<f32>, to: Point3<f32>| medium.transmittance(from, to).channels()[0];
+
+        // A level ray at height 0 sees the plain density
+        let level = transmittance(Point3::origin(), Point3::new(2.0, 0.0, 0.0));
+        assert!((level - (-1.0_f32).exp()).abs() < 1e-6);
+        assert!(transmittance(Point3::new(0.0, 2.0, 0.0), Point3::new(2.0, 2.0, 0.0)) > level);
+
+        // The density integrates to the same amount in both directions
+        let (low, high) = (Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 3.0, 0.0));
+        assert!((transmittance(low, high) - transmittance(high, low)).abs() < 1e-6);
+        let rise = 3.0_f32;
+        let expected = (-0.5 * 10.0_f32.sqrt() * (1.0 - (-rise).exp()) / rise).exp();
+        assert!((transmittance(low, high) - expected).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,83 @@
+//! Ambient occlusion: how much of the ambient light reaches a point, estimated from how many rays
+//! from the point into the hemisphere above its surface get away without hitting anything nearby
+
+use na::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::sampling::Rng;
+
+/// Settings of the ambient occlusion estimate
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AmbientOcclusion {
+    /// Number of rays per point
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+    /// Rays that get further than this without hitting anything count as unoccluded
+    #[serde(default = "default_distance")]
+    pub distance: f32,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        AmbientOcclusion {
+            samples: default_samples(),
+            distance: default_distance(),
+        }
+    }
+}
+
+fn default_samples() -> u32 {
+    16
+}
+
+fn default_distance() -> f32 {
+    1.0
+}
+
+impl AmbientOcclusion {
+    /// Fraction of the ambient light that reaches a surface with the normal, from 0 where it is fully
+    /// occluded to 1 where nothing is in the way. `occluded` tells whether anything is within `distance`
+    /// in the given direction. Directions close to the normal are tried more often, since the light
+    /// from there counts more
+    pub fn visibility(
+        &self,
+        normal: &Vector3<f32>,
+        rng: &mut Rng,
+        mut occluded: impl FnMut(Vector3<f32>) -> bool,
+    ) -> f32 {
+        if self.samples == 0 {
+            return 1.0;
+        }
+        let free = (0..self.samples)
+            .filter(|_| !occluded(rng.cosine_direction(normal)))
+            .count();
+        free as f32 / self.samples as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AmbientOcclusion;
+    use crate::sampling::Rng;
+    use na::Vector3;
+
+    #[test]
+    fn walls_block_part_of_the_hemisphere() {
+        let occlusion = AmbientOcclusion {
+            samples: 1000,
+            distance: 1.0,
+        };
+        let mut rng = Rng::new(1);
+        let up = Vector3::y();
+
+        assert_eq!(occlusion.visibility(&up, &mut rng, |_| false), 1.0);
+        assert_eq!(occlusion.visibility(&up, &mut rng, |_| true), 0.0);
+
+        // A wall on one side blocks half of the hemisphere
+        let half = occlusion.visibility(&up, &mut rng, |dir| dir.x > 0.0);
+        assert!((half - 0.5).abs() < 0.05, "{}", half);
+        // Light from close to the horizon counts less than light from above
+        let horizon = occlusion.visibility(&up, &mut rng, |dir| dir.y < 0.5);
+        assert!((horizon - 0.75).abs() < 0.05, "{}", horizon);
+    }
+}
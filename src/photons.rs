@@ -0,0 +1,197 @@
+//! Photon mapping: light traced from the light sources and stored where it lands, for the light that
+//! shadow rays can't find, like the light focused by mirrors
+
+use na::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::fb::Color;
+
+/// Settings of the photon mapping pre-pass
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PhotonMapping {
+    /// Number of photons sent out by all the lights together
+    pub count: u32,
+    /// Photons closer than this to a point light it up
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    /// Also gathers light that bounced off matte surfaces, not just the light focused by mirrors
+    #[serde(default)]
+    pub indirect: bool,
+}
+
+impl PhotonMapping {
+    /// Makes sure that photons are gathered from a disc with an area
+    pub fn check(&self) -> Result<(), String> {
+        if self.radius <= 0.0 || self.radius.is_nan() {
+            return Err(format!(
+                "Photon mapping radius must be positive, but it is {}",
+                self.radius
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_radius() -> f32 {
+    0.1
+}
+
+#[derive(Clone, Debug)]
+pub struct Photon {
+    pub pos: Point3<f32>,
+    /// Direction the photon was travelling in
+    pub dir: Vector3<f32>,
+    pub power: Color,
+    /// Axis along which the photon splits its part of the kd-tree
+    axis: usize,
+}
+
+impl Photon {
+    pub fn new(pos: Point3<f32>, dir: Vector3<f32>, power: Color) -> Self {
+        Photon {
+            pos,
+            dir,
+            power,
+            axis: 0,
+        }
+    }
+}
+
+/// Photons in a kd-tree. The tree is stored in the order of the photons: the middle photon of every
+/// range splits the rest of the range in two
+#[derive(Debug)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        build(&mut photons);
+        PhotonMap { photons }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Calls `f` for every photon closer than `radius` to the point
+    pub fn for_each_near(&self, point: Point3<f32>, radius: f32, mut f: impl FnMut(&Photon)) {
+        for_each_near(&self.photons, point, radius * radius, &mut f);
+    }
+
+    /// Light falling onto the surface with the normal at the point, estimated from the density of the
+    /// photons that hit its front side around the point
+    pub fn irradiance(&self, point: Point3<f32>, normal: Vector3<f32>, radius: f32) -> Color {
+        let mut total = Color::black();
+        self.for_each_near(point, radius, |photon| {
+            if photon.dir.dot(&normal) < 0.0 {
+                total = total + photon.power;
+            }
+        });
+        total * (std::f32::consts::PI * radius * radius).recip()
+    }
+}
+
+fn build(photons: &mut [Photon]) {
+    if photons.len() <= 1 {
+        return;
+    }
+
+    // The range is split along the axis where its photons are spread the widest
+    let (min, max) = photons.iter().fold(
+        (
+            Vector3::repeat(f32::INFINITY),
+            Vector3::repeat(f32::NEG_INFINITY),
+        ),
+        |(min, max), photon| {
+            (
+                min.zip_map(&photon.pos.coords, f32::min),
+                max.zip_map(&photon.pos.coords, f32::max),
+            )
+        },
+    );
+    let axis = (max - min).imax();
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        a.pos[axis]
+            .partial_cmp(&b.pos[axis])
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    photons[middle].axis = axis;
+
+    let (before, after) = photons.split_at_mut(middle);
+    build(before);
+    build(&mut after[1..]);
+}
+
+fn for_each_near(
+    photons: &[Photon],
+    point: Point3<f32>,
+    radius_squared: f32,
+    f: &mut impl FnMut(&Photon),
+) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let middle = photons.len() / 2;
+    let photon = &photons[middle];
+    if na::distance_squared(&photon.pos, &point) < radius_squared {
+        f(photon);
+    }
+    if photons.len() == 1 {
+        return;
+    }
+
+    let offset = point[photon.axis] - photon.pos[photon.axis];
+    let (near, far) = if offset < 0.0 {
+        (&photons[..middle], &photons[middle + 1..])
+    } else {
+        (&photons[middle + 1..], &photons[..middle])
+    };
+    for_each_near(near, point, radius_squared, f);
+    if offset * offset < radius_squared {
+        for_each_near(far, point, radius_squared, f);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Photon, PhotonMap};
+    use crate::{fb::Color, sampling::Rng};
+    use na::{Point3, Vector3};
+
+    #[test]
+    fn finds_the_photons_within_the_radius() {
+        let mut rng = Rng::new(1);
+        let photons = (0..2000)
+            .map(|_| {
+                let pos = Point3::new(
+                    rng.range(-1.0, 1.0),
+                    rng.range(-1.0, 1.0),
+                    rng.range(-1.0, 1.0),
+                );
+                Photon::new(pos, -Vector3::y(), Color::white())
+            })
+            .collect::<Vec<_>>();
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.len(), photons.len());
+
+        for &(x, y, z) in &[(0.0, 0.0, 0.0), (0.9, -0.5, 0.2), (1.5, 1.5, 1.5)] {
+            let point = Point3::new(x, y, z);
+            let mut found = Vec::new();
+            map.for_each_near(point, 0.3, |photon| found.push(photon.pos));
+            let expected = photons
+                .iter()
+                .filter(|photon| na::distance(&photon.pos, &point) < 0.3)
+                .count();
+            assert_eq!(found.len(), expected);
+            assert!(found.iter().all(|pos| na::distance(pos, &point) < 0.3));
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Observing and cancelling renders that run in the background
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::fb::Fb;
+
+/// Shared flag telling a render to stop. Renders check it between tiles
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Progress {
+    done: AtomicUsize,
+    total: usize,
+    started: Instant,
+    duration: Mutex<Option<Duration>>,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Self {
+        Progress {
+            done: AtomicUsize::new(0),
+            total,
+            started: Instant::now(),
+            duration: Mutex::new(None),
+        }
+    }
+
+    pub fn advance(&self, pixels: usize) {
+        self.done.fetch_add(pixels, Ordering::SeqCst);
+    }
+
+    pub fn finish(&self) {
+        *self.duration.lock().unwrap() = Some(self.started.elapsed());
+    }
+
+    /// Number of pixel samples traced so far
+    pub fn pixels_done(&self) -> usize {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// Number of pixel samples in the whole render
+    pub fn pixels_total(&self) -> usize {
+        self.total
+    }
+
+    /// Done part of the render, from 0 to 1
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.pixels_done() as f32 / self.total as f32
+        }
+    }
+
+    /// Time since the render started, or its total duration once it is finished
+    pub fn elapsed(&self) -> Duration {
+        self.duration
+            .lock()
+            .unwrap()
+            .unwrap_or_else(|| self.started.elapsed())
+    }
+
+    /// Estimated time until the render is done, extrapolated from the speed so far
+    pub fn eta(&self) -> Option<Duration> {
+        let done = self.pixels_done();
+        if done == 0 {
+            return None;
+        }
+
+        let left = self.total.saturating_sub(done);
+        Some(self.elapsed().mul_f64(left as f64 / done as f64))
+    }
+
+    /// Whether the render thread has stopped, either by finishing or by being cancelled
+    pub fn is_finished(&self) -> bool {
+        self.duration.lock().unwrap().is_some()
+    }
+}
+
+/// A render running on its own thread. Dropping the handle cancels the render
+pub struct RenderHandle {
+    progress: Arc<Progress>,
+    cancel: CancelToken,
+    images: Receiver<Fb>,
+    thread: Option<JoinHandle<Option<Fb>>>,
+}
+
+impl RenderHandle {
+    /// Runs `render` on a new thread. The render reports its progress through `Progress`,
+    /// may send intermediate images and should stop early once the token is cancelled
+    pub fn spawn(
+        total: usize,
+        render: impl FnOnce(&Progress, &CancelToken, &Sender<Fb>) -> Option<Fb> + Send + 'static,
+    ) -> Self {
+        let progress = Arc::new(Progress::new(total));
+        let cancel = CancelToken::new();
+        let (sender, images) = std::sync::mpsc::channel();
+
+        let thread = {
+            let progress = Arc::clone(&progress);
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                // Marks the render as finished even if it panics, so nobody waits for it forever
+                struct Finish(Arc<Progress>);
+                impl Drop for Finish {
+                    fn drop(&mut self) {
+                        self.0.finish();
+                    }
+                }
+
+                let finish = Finish(progress);
+                render(&finish.0, &cancel, &sender)
+            })
+        };
+
+        RenderHandle {
+            progress,
+            cancel,
+            images,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.progress.is_finished()
+    }
+
+    /// The newest intermediate image sent since the last call, if any
+    pub fn latest_image(&self) -> Option<Fb> {
+        self.images.try_iter().last()
+    }
+
+    /// Blocks until the render is done. Returns `None` if it was cancelled
+    pub fn wait(mut self) -> Option<Fb> {
+        self.thread
+            .take()
+            .and_then(|thread| thread.join().expect("Render thread panicked"))
+    }
+}
+
+impl Drop for RenderHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.cancel.cancel();
+            let _ = thread.join();
+        }
+    }
+}
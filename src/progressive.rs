@@ -0,0 +1,130 @@
+//! Progressive rendering: the image is refined by a series of passes, each adding one sample per pixel
+//! at a different sub-pixel offset. Passes are traced bucket by bucket, spiraling out from the middle
+//! of the image, so the result can be shown long before the first pass is done.
+
+#[cfg(not(feature = "wasm"))]
+use rayon::prelude::*;
+
+use crate::{
+    fb::{Color, Fb},
+    raytrace::Renderer,
+    sampling,
+    scene::Scene,
+    tiles::{self, Tile, TileOrder},
+};
+
+pub const BUCKET_SIZE: u16 = 32;
+
+pub struct Progressive {
+    renderer: Renderer,
+    region: Tile,
+    accum: Fb,
+    samples: Vec<u32>,
+    passes: u32,
+}
+
+impl Progressive {
+    /// `scene.multisample` is ignored, since the passes already sample every pixel many times.
+    /// With a crop window only that part of the image is traced
+    pub fn new(scene: Scene) -> Self {
+        let region = scene.region();
+
+        Progressive {
+            renderer: crate::prepare_scene(scene),
+            region,
+            accum: Fb::new_empty(region.width, region.height),
+            samples: vec![0; region.area()],
+            passes: 0,
+        }
+    }
+
+    /// Number of finished passes, which is also the number of samples in every pixel
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    /// Buckets of the current pass in the order they should be traced
+    pub fn buckets(&self) -> Vec<Tile> {
+        tiles::tiles_in(self.region, BUCKET_SIZE, TileOrder::Spiral)
+    }
+
+    /// Adds one sample of the current pass to every pixel of the bucket
+    pub fn render_bucket(&mut self, bucket: Tile) {
+        let offset = (radical_inverse(self.passes, 2), radical_inverse(self.passes, 3));
+        let time = radical_inverse(self.passes, 5);
+        let renderer = &self.renderer;
+
+        let pixels = bucket.pixels().collect::<Vec<_>>();
+
+        let sample = |&(x, y): &(u16, u16)| {
+            // Every pixel starts at a different moment, so moving objects blur into noise instead of copies
+            let time = (time + sampling::pixel_offset(x, y)).fract();
+            (x, y, renderer.sample(x as f32 + offset.0, y as f32 + offset.1, time))
+        };
+
+        #[cfg(feature = "wasm")]
+        let colors = pixels.iter().map(sample).collect::<Vec<_>>();
+        #[cfg(not(feature = "wasm"))]
+        let colors = pixels.par_iter().map(sample).collect::<Vec<_>>();
+
+        let width = self.accum.width();
+        for (x, y, color) in colors {
+            let (x, y) = (x - self.region.x, y - self.region.y);
+            self.accum.set(x, y, self.accum.get(x, y) + color);
+            self.samples[y as usize * width as usize + x as usize] += 1;
+        }
+    }
+
+    /// Must be called once all the buckets of the current pass are rendered
+    pub fn finish_pass(&mut self) {
+        self.passes += 1;
+    }
+
+    /// Traces a whole pass at once
+    pub fn render_pass(&mut self) {
+        for bucket in self.buckets() {
+            self.render_bucket(bucket);
+        }
+        self.finish_pass();
+    }
+
+    /// The current estimate of the image, or of its crop window. Pixels that were not sampled yet are black
+    pub fn image(&self) -> Fb {
+        let width = self.accum.width();
+        Fb::from_func(self.accum.width(), self.accum.height(), |x, y| {
+            match self.samples[y as usize * width as usize + x as usize] {
+                0 => Color::black(),
+                n => self.accum.get(x, y) * (n as f32).recip(),
+            }
+        })
+    }
+}
+
+/// Van der Corput sequence in the given base, used for well-distributed sub-pixel offsets.
+/// The first element is 0, so the first pass samples the same points as a regular render
+fn radical_inverse(mut i: u32, base: u32) -> f32 {
+    let inv_base = (base as f32).recip();
+    let mut factor = inv_base;
+    let mut result = 0.0;
+
+    while i > 0 {
+        result += (i % base) as f32 * factor;
+        i /= base;
+        factor *= inv_base;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::radical_inverse;
+
+    #[test]
+    fn radical_inverse_base_two() {
+        assert_eq!(radical_inverse(0, 2), 0.0);
+        assert_eq!(radical_inverse(1, 2), 0.5);
+        assert_eq!(radical_inverse(2, 2), 0.25);
+        assert_eq!(radical_inverse(3, 2), 0.75);
+    }
+}
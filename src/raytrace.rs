@@ -1,11 +1,16 @@
+#[cfg(not(feature = "wasm"))]
+use rayon::prelude::*;
+
+use std::sync::{Arc, Mutex};
+
 use na::{Isometry3, Point3, UnitQuaternion, Vector3};
 use nc::{
-    query::{Ray, RayIntersection},
+    bounding_volume::{BoundingVolume, AABB},
+    query::{Ray, RayCast, RayIntersection},
     shape::ShapeHandle,
     pipeline::{
         object::{
             CollisionGroups,
-            CollisionObject as CollisionObject_,
             GeometricQueryType
         },
         world::CollisionWorld as CollisionWorld_
@@ -13,33 +18,169 @@ use nc::{
 };
 
 use crate::{
+    environment::Environment,
     fb::{Color, Fb},
-    material::{Material, Phong, Reflect},
+    inspect::{Aov, Hit, RayNode, ShadowRay},
+    material::{Material, Phong, Reflect, Subsurface},
+    media::{Fog, Medium},
+    occlusion::AmbientOcclusion,
+    photons::{Photon, PhotonMap, PhotonMapping},
+    sampling,
+    tiles::Tile,
 };
 
 type CollisionWorld = CollisionWorld_<f32, WorldData>;
-type CollisionObject = CollisionObject_<f32, WorldData>;
+
+/// Number of points along a ray at which the light scattered by a medium is gathered
+const MEDIUM_STEPS: usize = 32;
+/// Isotropic phase function, scaled like the diffuse light of surfaces
+const PHASE: f32 = 0.25;
+/// Points of a medium closer to a light than this are lit as if they were this far, which keeps rays
+/// passing right by a light from picking up huge amounts of light
+const MIN_LIGHT_DISTANCE: f32 = 0.25;
+/// How far past the surface of a volume rays continue when they cross it
+const VOLUME_MARGIN: f32 = 1e-4;
+/// Surfaces of volumes and see-through objects that a ray and the rays traced from it can cross.
+/// Crossings don't count as steps, so the objects behind them are still reflected as often
+const MAX_CROSSINGS: usize = 64;
+/// Number of random walks that gather the light scattered below a translucent surface
+const SUBSURFACE_WALKS: usize = 8;
+/// Walks that scatter more often than this are absorbed
+const SUBSURFACE_BOUNCES: usize = 32;
+/// Photons that bounce more often than this are absorbed
+const PHOTON_BOUNCES: usize = 8;
+/// Number of photons of a light that are traced one after the other, while batches run in parallel
+const PHOTON_BATCH: usize = 1024;
+
+/// Collision groups of the objects seen by rays from the camera, by shadow rays and by reflected rays
+const CAMERA_GROUP: usize = 0;
+const SHADOW_GROUP: usize = 1;
+const REFLECTION_GROUP: usize = 2;
+/// Lights that only shine on some of the objects get a group each from here on, which those objects are in
+const FIRST_LIGHT_GROUP: usize = 3;
+/// Number of lights that can shine on only some of the objects
+pub const MAX_LINKED_LIGHTS: usize = 27;
+
+/// Groups of a ray that only hits the objects in `group`
+fn seen_by(group: usize) -> CollisionGroups {
+    CollisionGroups::new().with_whitelist(&[group])
+}
 
 pub struct RaytraceObject {
+    /// Position at the start of the frame
     pub pos: Isometry3<f32>,
+    /// Position at the end of the frame, for objects that move while the shutter is open
+    pub motion: Option<Isometry3<f32>>,
     pub shape: ShapeHandle<f32>,
     pub mat: Material,
+    pub visibility: Visibility,
 }
 
-impl RaytraceObject {
-    fn unpack(self) -> (Isometry3<f32>, ShapeHandle<f32>, WorldData) {
-        (self.pos, self.shape, WorldData { mat: self.mat })
+/// Which rays see an object
+#[derive(Clone, Copy, Debug)]
+pub struct Visibility {
+    pub camera: bool,
+    /// Whether the object blocks the light of the lights
+    pub shadows: bool,
+    pub reflections: bool,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility {
+            camera: true,
+            shadows: true,
+            reflections: true,
+        }
     }
 }
 
+#[derive(Clone)]
 struct WorldData {
+    /// Position of the object in the scene, reported by the pixel inspector
+    index: usize,
+    /// Position in `RtConfig::interiors`, if rays have to be traced inside of the object
+    interior: Option<usize>,
     mat: Material,
+    /// The rays that see the object, and the lights that shine on it if they don't shine on everything
+    groups: CollisionGroups,
+}
+
+impl WorldData {
+    /// Whether the object is one of the `media` that a ray is inside of
+    fn in_media(&self, media: &rpds::Stack<usize>) -> bool {
+        self.interior.map_or(false, |interior| media.iter().any(|&v| v == interior))
+    }
+
+    /// Whether rays inside of the `media` go on through the surface untouched. Volumes have no surface,
+    /// and see-through objects tint the light once, where it enters them, like for shadow rays
+    fn lets_through(&self, media: &rpds::Stack<usize>) -> bool {
+        self.mat.volume.is_some() || (!self.mat.is_opaque() && self.in_media(media))
+    }
+}
+
+/// An object that moves during the frame. It can't be in the collision world, since it is somewhere
+/// else for every ray, so rays are cast against it one by one
+struct MovingObject {
+    start: Isometry3<f32>,
+    end: Isometry3<f32>,
+    shape: ShapeHandle<f32>,
+    /// Contains the object during the whole frame. Rays that miss it aren't cast at the object
+    bounds: AABB<f32>,
+    data: WorldData,
+}
+
+/// An object with a volume, subsurface scattering or a surface that lets light through. Rays inside
+/// of it have to be cast at it from the inside to find where they leave it, which the collision world
+/// doesn't do
+struct Interior {
+    start: Isometry3<f32>,
+    end: Option<Isometry3<f32>>,
+    shape: ShapeHandle<f32>,
+    data: WorldData,
+}
+
+impl Interior {
+    fn position(&self, time: f32) -> Isometry3<f32> {
+        match &self.end {
+            Some(end) => interpolate(&self.start, end, time),
+            None => self.start,
+        }
+    }
+}
+
+/// Box that contains the shape at every position between `start` and `end`
+fn swept_aabb(shape: &ShapeHandle<f32>, start: &Isometry3<f32>, end: &Isometry3<f32>) -> AABB<f32> {
+    let aabb = shape.aabb(start).merged(&shape.aabb(end));
+    if start.rotation == end.rotation {
+        return aabb;
+    }
+
+    // While it turns, the shape stays within its reach of its local origin, which moves in a straight line
+    let local = shape.local_aabb();
+    let reach = local.center().coords.norm() + local.half_extents().norm();
+    let origin = |pos: &Isometry3<f32>| Point3::from(pos.translation.vector);
+    let path = AABB::new(origin(start), origin(start)).merged(&AABB::new(origin(end), origin(end)));
+    aabb.merged(&path.loosened(reach))
+}
+
+/// Position between `start` at time 0 and `end` at time 1
+fn interpolate(start: &Isometry3<f32>, end: &Isometry3<f32>, time: f32) -> Isometry3<f32> {
+    let translation = start.translation.vector.lerp(&end.translation.vector, time);
+    // Opposite rotations have no shortest path between them, so the object snaps halfway through
+    let rotation = start
+        .rotation
+        .try_slerp(&end.rotation, time, 1e-6)
+        .unwrap_or(if time < 0.5 { start.rotation } else { end.rotation });
+    Isometry3::from_parts(translation.into(), rotation)
 }
 
 pub struct LightSource {
     pub pos: Isometry3<f32>,
     pub brightness: Color,
     pub kind: LightSourceKind,
+    /// Positions of the objects the light shines on in the list of objects. `None` shines on all of them
+    pub objects: Option<Vec<usize>>,
 }
 
 impl LightSource {
@@ -51,6 +192,7 @@ impl LightSource {
             ),
             brightness,
             kind: LightSourceKind::Point,
+            objects: None,
         }
     }
 }
@@ -62,37 +204,98 @@ pub enum LightSourceKind {
 struct RtConfig {
     ambient: Color,
     world: CollisionWorld,
+    moving: Vec<MovingObject>,
+    /// Objects that rays can be inside of. They are also in `world` or `moving`, where rays enter them
+    interiors: Vec<Interior>,
     lights: Vec<LightSource>,
+    fog: Option<Fog>,
+    photons: Option<Arc<Photons>>,
+    occlusion: Option<AmbientOcclusion>,
+    environment: Option<Environment>,
+    /// Distance from the origin within which every object of a finite size lies
+    extent: f32,
+    /// Group of every light that only shines on some of the objects
+    light_groups: Vec<Option<usize>>,
+}
+
+impl RtConfig {
+    /// Whether the light with the given position in `lights` shines on the object
+    fn lights(&self, light: usize, object: &WorldData) -> bool {
+        self.light_groups[light].map_or(true, |group| object.groups.is_member_of(group))
+    }
+
+    /// Whether light can be dimmed by anything but opaque objects
+    fn has_media(&self) -> bool {
+        self.fog.is_some()
+            || self
+                .interiors
+                .iter()
+                .any(|obj| obj.data.mat.volume.is_some() || !obj.data.mat.is_opaque())
+    }
 }
 
 struct RayData {
     ray: Ray<f32>,
+    /// Moment of the frame the ray is traced at, from 0 to 1. Every ray traced because of this one
+    /// sees the scene at the same moment
+    time: f32,
     steps_left: usize,
+    crossings_left: usize,
     refraction_stack: rpds::Stack<f32>,
+    /// Objects the ray is inside of, as positions in `RtConfig::interiors`. The last one entered is on top
+    media: rpds::Stack<usize>,
+    /// Objects the ray can hit
+    groups: CollisionGroups,
 }
 
 impl RayData {
     fn refract(&self, ray: Ray<f32>, index: f32) -> Self {
         RayData {
             ray,
+            time: self.time,
             steps_left: self.steps_left - 1,
+            crossings_left: self.crossings_left,
             refraction_stack: self.refraction_stack.push(index),
+            media: self.media.clone(),
+            groups: self.groups,
         }
     }
 
     fn unrefract(&self, ray: Ray<f32>) -> Self {
         RayData {
             ray,
+            time: self.time,
             steps_left: self.steps_left - 1,
+            crossings_left: self.crossings_left,
             refraction_stack: self.refraction_stack.pop().expect("Refraction stack empty"),
+            media: self.media.clone(),
+            groups: self.groups,
         }
     }
 
-    fn push(&self, ray: Ray<f32>) -> Self {
+    /// Reflects the ray, which then only hits the objects that are visible in reflections
+    fn reflect(&self, ray: Ray<f32>) -> Self {
         RayData {
             ray,
+            time: self.time,
             steps_left: self.steps_left - 1,
+            crossings_left: self.crossings_left,
+            refraction_stack: self.refraction_stack.clone(),
+            media: self.media.clone(),
+            groups: seen_by(REFLECTION_GROUP),
+        }
+    }
+
+    /// Crosses the surface of a volume or a see-through object, which doesn't count as a step
+    fn cross(&self, ray: Ray<f32>, media: rpds::Stack<usize>) -> Self {
+        RayData {
+            ray,
+            time: self.time,
+            steps_left: self.steps_left,
+            crossings_left: self.crossings_left - 1,
             refraction_stack: self.refraction_stack.clone(),
+            media,
+            groups: self.groups,
         }
     }
 }
@@ -105,50 +308,326 @@ pub fn raytrace(
     objects: Vec<RaytraceObject>,
     lights: Vec<LightSource>,
 ) -> Fb {
-    let fov = fov.to_radians();
+    Renderer::new(size, fov, steps, camera, objects, lights).render()
+}
 
-    let mut world = CollisionWorld::new(0.0);
-    for obj in objects {
-        let (pos, shape, data) = obj.unpack();
-        world.add(
-            pos,
-            shape,
-            CollisionGroups::new(),
-            GeometricQueryType::Contacts(0.0, 0.0),
-            data,
-        );
+/// A scene prepared for tracing, which can be sampled at arbitrary points of the image plane
+pub struct Renderer {
+    size: (u16, u16),
+    steps: usize,
+    plane_distance: f32,
+    camera: Isometry3<f32>,
+    /// Camera position at the end of the frame, if the camera moves
+    camera_end: Option<Isometry3<f32>>,
+    /// Part of the frame during which the shutter is open
+    shutter: (f32, f32),
+    config: RtConfig,
+}
+
+impl Renderer {
+    pub fn new(
+        size: (u16, u16),
+        fov: f32,
+        steps: usize,
+        camera: Isometry3<f32>,
+        objects: Vec<RaytraceObject>,
+        lights: Vec<LightSource>,
+    ) -> Self {
+        let fov = fov.to_radians();
+
+        // Objects are in the groups of the lights that are linked to them
+        let mut memberships = vec![Vec::new(); objects.len()];
+        let mut linked = 0;
+        let light_groups = lights
+            .iter()
+            .map(|light| {
+                // `Scene::check` rejects scenes that link too many lights or link to missing objects
+                let objects = light.objects.as_ref()?;
+                assert!(linked < MAX_LINKED_LIGHTS, "Too many lights are linked to objects");
+                let group = FIRST_LIGHT_GROUP + linked;
+                linked += 1;
+                for &object in objects {
+                    assert!(object < memberships.len(), "A light is linked to the missing object {}", object);
+                    memberships[object].push(group);
+                }
+                Some(group)
+            })
+            .collect();
+
+        let mut world = CollisionWorld::new(0.0);
+        let mut moving = Vec::new();
+        let mut interiors = Vec::new();
+        let mut extent = 0.0_f32;
+        for ((index, obj), mut membership) in objects.into_iter().enumerate().zip(memberships) {
+            let bounds = match &obj.motion {
+                Some(end) => swept_aabb(&obj.shape, &obj.pos, end),
+                None => obj.shape.aabb(&obj.pos),
+            };
+            let corner = bounds.center().coords.norm() + bounds.half_extents().norm();
+            if corner.is_finite() {
+                extent = extent.max(corner);
+            }
+            let has_interior =
+                obj.mat.volume.is_some() || obj.mat.subsurface.part > 0.0 || !obj.mat.is_opaque();
+            let interior = if has_interior { Some(interiors.len()) } else { None };
+            let visibility = obj.visibility;
+            let groups = [
+                (CAMERA_GROUP, visibility.camera),
+                (SHADOW_GROUP, visibility.shadows),
+                (REFLECTION_GROUP, visibility.reflections),
+            ];
+            membership.extend(groups.iter().filter(|(_, visible)| *visible).map(|(group, _)| group));
+            let data = WorldData {
+                index,
+                interior,
+                mat: obj.mat,
+                groups: CollisionGroups::new().with_membership(&membership),
+            };
+            if has_interior {
+                interiors.push(Interior {
+                    start: obj.pos,
+                    end: obj.motion,
+                    shape: obj.shape.clone(),
+                    data: data.clone(),
+                });
+            }
+            match obj.motion {
+                Some(end) => moving.push(MovingObject {
+                    start: obj.pos,
+                    end,
+                    shape: obj.shape,
+                    bounds,
+                    data,
+                }),
+                None => {
+                    let groups = data.groups;
+                    world.add(
+                        obj.pos,
+                        obj.shape,
+                        groups,
+                        GeometricQueryType::Contacts(0.0, 0.0),
+                        data,
+                    );
+                }
+            }
+        }
+        world.update();
+
+        let plane_distance = (fov / 2.0).tan().recip();
+
+        let config = RtConfig {
+            ambient: Color::new(0.0, 0.0, 0.1),
+            world,
+            moving,
+            interiors,
+            lights,
+            fog: None,
+            photons: None,
+            occlusion: None,
+            environment: None,
+            extent,
+            light_groups,
+        };
+
+        Renderer {
+            size,
+            steps,
+            plane_distance,
+            camera,
+            camera_end: None,
+            shutter: (0.0, 1.0),
+            config,
+        }
     }
-    world.update();
 
-    let plane_distance = (fov / 2.0).tan().recip();
+    /// Moves the camera from its position to `camera_end` during the frame. Rays are only traced
+    /// while the shutter is open, between the given fractions of the frame
+    pub fn with_motion(mut self, camera_end: Option<Isometry3<f32>>, shutter: (f32, f32)) -> Self {
+        self.camera_end = camera_end;
+        self.shutter = shutter;
+        self
+    }
 
-    let config = RtConfig {
-        ambient: Color::new(0.0, 0.0, 0.1),
-        world,
-        lights,
-    };
+    /// Fills the space between the objects with fog
+    pub fn with_fog(mut self, fog: Option<Fog>) -> Self {
+        self.config.fog = fog;
+        self
+    }
 
-    let func = |x, y| {
-        let ray = RayData {
-            ray: get_ray(to_uv(x, y, size), plane_distance, camera),
-            steps_left: steps,
+    /// Traces photons from the lights, which light up matte surfaces with the light that mirrors focus
+    /// onto them, and with the light bouncing between surfaces if `indirect` is set. Photons that are
+    /// already in the cache are used instead
+    pub fn with_photon_mapping(mut self, settings: Option<PhotonMapping>, cache: &PhotonCache) -> Self {
+        let photons = settings.map(|settings| {
+            // A renderer that starts while the photons are traced waits for them instead of tracing its own
+            let mut cached = cache.0.lock().unwrap();
+            cached
+                .get_or_insert_with(|| Arc::new(trace_photons(&settings, &self.config)))
+                .clone()
+        });
+        self.config.photons = photons;
+        self
+    }
+
+    /// Scales the ambient light by how much of it nearby objects let through
+    pub fn with_ambient_occlusion(mut self, settings: Option<AmbientOcclusion>) -> Self {
+        self.config.occlusion = settings;
+        self
+    }
+
+    /// Lights the scene with the environment, which is also seen where rays leave the scene
+    pub fn with_environment(mut self, environment: Option<Environment>) -> Self {
+        self.config.environment = environment;
+        self
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    /// `shutter_time` goes from 0 when the shutter opens to 1 when it closes
+    fn camera_ray(&self, x: f32, y: f32, shutter_time: f32) -> RayData {
+        let time = self.shutter.0 + (self.shutter.1 - self.shutter.0) * shutter_time;
+        let camera = match &self.camera_end {
+            Some(end) => interpolate(&self.camera, end, time),
+            None => self.camera,
+        };
+
+        RayData {
+            ray: get_ray(to_uv_f(x, y, self.size), self.plane_distance, camera),
+            time,
+            steps_left: self.steps,
+            crossings_left: MAX_CROSSINGS,
             refraction_stack: rpds::Stack::new().push(1.0),
+            media: rpds::Stack::new(),
+            groups: seen_by(CAMERA_GROUP),
+        }
+    }
+
+    /// Traces a single ray through the image plane. Coordinates are in pixels, so `(x + 0.5, y + 0.5)`
+    /// lies in the middle of the pixel `(x, y)`. `time` picks the moment while the shutter is open,
+    /// from 0 to 1, and only matters if something moves
+    pub fn sample(&self, x: f32, y: f32, time: f32) -> Color {
+        cast_ray(self.camera_ray(x, y, time), &self.config, None)
+    }
+
+    /// Like `sample`, but also records every ray traced for the sample. The scene is inspected
+    /// halfway through the exposure
+    pub fn inspect(&self, x: f32, y: f32) -> RayNode {
+        let ray = self.camera_ray(x, y, 0.5);
+        let mut node = RayNode::new(ray.ray);
+        cast_ray(ray, &self.config, Some(&mut node));
+        node
+    }
+
+    /// Shows the given property of the surfaces seen through the pixels of the tile,
+    /// instead of their shaded color
+    pub fn render_aov(&self, tile: Tile, aov: Aov) -> Fb {
+        // Scenes without ambient occlusion show it with the default settings
+        let occlusion = self.config.occlusion.clone().unwrap_or_default();
+        let first_hit = |x: u16, y: u16| {
+            let ray = self.camera_ray((tile.x + x) as f32, (tile.y + y) as f32, 0.5);
+            first_interference(&ray, &self.config).map(|(data, int)| {
+                let point = ray.ray.origin + ray.ray.dir * int.toi;
+                Hit {
+                    object: data.index,
+                    material: data.mat.clone(),
+                    point,
+                    normal: int.normal,
+                    depth: int.toi * ray.ray.dir.norm(),
+                    uv: int.uvs,
+                    occlusion: (aov == Aov::Occlusion).then(|| {
+                        // Every pixel is traced at the same time, so the pixel is mixed into the seed
+                        let pixel = u64::from(tile.x + x) << 16 | u64::from(tile.y + y);
+                        let seed = sampling::seed(ray.time, &point) ^ pixel;
+                        let media = &ray.media;
+                        ambient_visibility(point, &int.normal, ray.time, media, seed, &occlusion, &self.config)
+                    }),
+                    shadow_rays: Vec::new(),
+                    reflection: None,
+                }
+            })
         };
 
-        cast_ray(ray, &config)
-    };
+        let pixels = tile.pixels().collect::<Vec<_>>();
+        let hit = |&(x, y): &(u16, u16)| first_hit(x - tile.x, y - tile.y);
 
-    #[cfg(feature = "wasm")]
-    return Fb::from_func(size.0, size.1, func);
+        #[cfg(feature = "wasm")]
+        let hits = pixels.iter().map(hit).collect::<Vec<_>>();
+        #[cfg(not(feature = "wasm"))]
+        let hits = pixels.par_iter().map(hit).collect::<Vec<_>>();
 
-    #[cfg(not(feature = "wasm"))]
-    return Fb::from_par_func(size.0, size.1, func);
+        // Depth is scaled so that the furthest surface is black
+        let max_depth = hits
+            .iter()
+            .flatten()
+            .map(|hit| hit.depth)
+            .fold(0.0, f32::max);
+
+        Fb::from_func(tile.width, tile.height, |x, y| {
+            match &hits[y as usize * tile.width as usize + x as usize] {
+                Some(hit) => aov.color(hit, max_depth),
+                None => Color::black(),
+            }
+        })
+    }
+
+    /// Color of the pixel `(x, y)`. With multisampling the pixel is averaged over a 2x2 grid of samples.
+    /// The samples are spread over the exposure, starting at a different moment for every pixel
+    pub fn pixel(&self, x: u16, y: u16, multisample: bool) -> Color {
+        const SAMPLES: u16 = 2;
+        let time = sampling::pixel_offset(x, y);
+
+        if multisample {
+            let count = (SAMPLES as f32).powi(2);
+            (0..SAMPLES)
+                .flat_map(|dx| (0..SAMPLES).map(move |dy| (dx, dy)))
+                .map(|(dx, dy)| {
+                    let stratum = (dx * SAMPLES + dy) as f32;
+                    self.sample(
+                        x as f32 + dx as f32 / SAMPLES as f32,
+                        y as f32 + dy as f32 / SAMPLES as f32,
+                        (stratum + time) / count,
+                    ) * count.recip()
+                })
+                .fold(Color::black(), |acc, a| acc + a)
+        } else {
+            self.sample(x as f32, y as f32, time)
+        }
+    }
+
+    pub fn render(&self) -> Fb {
+        let (width, height) = self.size;
+        self.render_tile(
+            Tile {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            false,
+        )
+    }
+
+    pub fn render_tile(&self, tile: Tile, multisample: bool) -> Fb {
+        let func = |x, y| self.pixel(tile.x + x, tile.y + y, multisample);
+
+        #[cfg(feature = "wasm")]
+        return Fb::from_func(tile.width, tile.height, func);
+
+        #[cfg(not(feature = "wasm"))]
+        return Fb::from_par_func(tile.width, tile.height, func);
+    }
 }
 
 fn to_uv(x: u16, y: u16, size: (u16, u16)) -> (f32, f32) {
+    to_uv_f(x as f32, y as f32, size)
+}
+
+fn to_uv_f(x: f32, y: f32, size: (u16, u16)) -> (f32, f32) {
     (
-        (x as f32 / size.0 as f32 * 2.0 - 1.0) * (size.0 as f32 / size.1 as f32),
-        -(y as f32 / size.1 as f32 * 2.0 - 1.0),
+        (x / size.0 as f32 * 2.0 - 1.0) * (size.0 as f32 / size.1 as f32),
+        -(y / size.1 as f32 * 2.0 - 1.0),
     )
 }
 
@@ -157,49 +636,616 @@ fn get_ray(uv: (f32, f32), plane_distance: f32, camera: Isometry3<f32>) -> Ray<f
     Ray::new(Point3::origin(), direction).transform_by(&camera)
 }
 
-fn cast_ray(ray: RayData, config: &RtConfig) -> Color {
-    if ray.steps_left == 0 {
+/// Traces the ray. If `node` is given, the hit and all the rays traced from it are recorded into it
+fn cast_ray(ray: RayData, config: &RtConfig, mut node: Option<&mut RayNode>) -> Color {
+    if ray.steps_left == 0 || ray.crossings_left == 0 {
         return Color::black();
     }
 
-    if let Some((obj, int)) = first_interference(ray.ray, &config.world) {
-        let normal = Ray::new(ray.ray.origin + ray.ray.dir * int.toi, int.normal);
-        get_color(
-            ray,
-            config,
-            GetColorArgs {
-                normal,
-                mat: &obj.data().mat,
-            },
-        )
+    let interference = first_interference(&ray, config);
+    let medium = medium_of(&ray, config).map(|medium| {
+        let length = match &interference {
+            Some((_, int)) => int.toi * ray.ray.dir.norm(),
+            None => escape_distance(&ray, config),
+        };
+        through_medium(&ray, length, medium, config)
+    });
+
+    let color = match interference {
+        // The ray goes on into volumes or out of them, and out of see-through objects
+        Some((data, int)) if data.lets_through(&ray.media) => {
+            let interior = data.interior.expect("Object without an interior");
+            let dir = ray.ray.dir;
+            let point = ray.ray.origin + dir * int.toi + dir.normalize() * VOLUME_MARGIN;
+            let media = crossed(&ray.media, interior);
+            cast_ray(ray.cross(Ray::new(point, dir), media), config, node.as_deref_mut())
+        }
+        Some((data, int)) => {
+            let point = ray.ray.origin + ray.ray.dir * int.toi;
+            // Seen from inside of the object, the surface faces inwards
+            let facing_away = data.in_media(&ray.media) && int.normal.dot(&ray.ray.dir) > 0.0;
+            let normal = Ray::new(point, if facing_away { -int.normal } else { int.normal });
+
+            // Light from behind see-through surfaces, which the ray goes on to find
+            let behind = if data.mat.is_opaque() {
+                None
+            } else {
+                let interior = data.interior.expect("See-through object without an interior");
+                let dir = ray.ray.dir;
+                let through = Ray::new(point + dir.normalize() * VOLUME_MARGIN, dir);
+                let color = cast_ray(ray.cross(through, crossed(&ray.media, interior)), config, None);
+                Some(color * data.mat.transmission(int.uvs))
+            };
+
+            let mut hit = node.as_ref().map(|_| Hit {
+                object: data.index,
+                material: data.mat.clone(),
+                point: normal.origin,
+                normal: normal.dir,
+                depth: int.toi * ray.ray.dir.norm(),
+                uv: int.uvs,
+                occlusion: None,
+                shadow_rays: Vec::new(),
+                reflection: None,
+            });
+
+            let color = get_color(
+                ray,
+                config,
+                GetColorArgs {
+                    normal,
+                    mat: &data.mat,
+                    object: data,
+                },
+                hit.as_mut(),
+            );
+            let color = match behind {
+                Some(behind) => color * data.mat.coverage(int.uvs) + behind,
+                None => color,
+            };
+
+            if let Some(node) = node.as_mut() {
+                node.hit = hit;
+            }
+            color
+        }
+        None => match &config.environment {
+            Some(environment) => environment.radiance(&ray.ray.dir),
+            None => config.ambient,
+        },
+    };
+
+    let color = match medium {
+        Some((scattered, through)) => color * through + scattered,
+        None => color,
+    };
+    if let Some(node) = node {
+        node.color = color;
+    }
+    color
+}
+
+/// The closest object hit by the ray, with the moving objects where they are at the time of the ray
+fn first_interference<'a>(ray: &RayData, config: &'a RtConfig) -> Option<(&'a WorldData, RayIntersection<f32>)> {
+    first_interference_where(&ray.ray, ray.time, &ray.media, config, &ray.groups, |_| true)
+}
+
+/// Like `first_interference`, but only objects in `groups` that pass `filter` are hit
+fn first_interference_where<'a>(
+    ray: &Ray<f32>,
+    time: f32,
+    media: &rpds::Stack<usize>,
+    config: &'a RtConfig,
+    groups: &CollisionGroups,
+    filter: impl Fn(&WorldData) -> bool,
+) -> Option<(&'a WorldData, RayIntersection<f32>)> {
+    let mut first: Option<(&WorldData, RayIntersection<f32>)> = None;
+    for_each_interference(ray, time, media, config, groups, |data, isect| {
+        if filter(data) && first.as_ref().map_or(true, |(_, first)| isect.toi < first.toi) {
+            first = Some((data, isect));
+        }
+    });
+    first
+}
+
+/// Calls `f` for every object in `groups` hit by the ray, with the moving objects where they are at `time`.
+/// Objects in `media`, which the ray starts inside of, are hit where the ray leaves them
+fn for_each_interference<'a>(
+    ray: &Ray<f32>,
+    time: f32,
+    media: &rpds::Stack<usize>,
+    config: &'a RtConfig,
+    groups: &CollisionGroups,
+    mut f: impl FnMut(&'a WorldData, RayIntersection<f32>),
+) {
+    let seen = |data: &WorldData| data.groups.can_interact_with_groups(groups);
+
+    for (_, obj, isect) in config.world.interferences_with_ray(ray, groups) {
+        if !obj.data().in_media(media) {
+            f(obj.data(), isect);
+        }
+    }
+
+    for obj in config.moving.iter().filter(|obj| seen(&obj.data) && !obj.data.in_media(media)) {
+        // Boxes without a finite size aren't worth testing, since the ray hits most of them
+        let finite = obj.bounds.half_extents().norm().is_finite();
+        if finite && !obj.bounds.intersects_ray(&Isometry3::identity(), ray) {
+            continue;
+        }
+        let pos = interpolate(&obj.start, &obj.end, time);
+        if let Some(isect) = obj
+            .shape
+            .as_ray_cast()
+            .and_then(|shape| shape.toi_and_normal_with_ray(&pos, ray, true))
+        {
+            f(&obj.data, isect);
+        }
+    }
+
+    for &interior in media.iter() {
+        let obj = &config.interiors[interior];
+        if !seen(&obj.data) {
+            continue;
+        }
+        if let Some(isect) = obj
+            .shape
+            .as_ray_cast()
+            .and_then(|shape| shape.toi_and_normal_with_ray(&obj.position(time), ray, false))
+        {
+            f(&obj.data, isect);
+        }
+    }
+}
+
+/// The media after crossing the surface of the interior: with it if the ray entered it, without it if it left
+fn crossed(media: &rpds::Stack<usize>, interior: usize) -> rpds::Stack<usize> {
+    if media.iter().any(|&v| v == interior) {
+        without(media, interior)
     } else {
-        config.ambient
+        media.push(interior)
     }
 }
 
-fn first_interference(
-    ray: Ray<f32>,
-    world: &CollisionWorld,
-) -> Option<(&CollisionObject, RayIntersection<f32>)> {
-    world
-        .interferences_with_ray(&ray, &CollisionGroups::new())
-        .min_by(|(_, _, isect1), (_, _, isect2)| isect1.toi.partial_cmp(&isect2.toi).unwrap_or(std::cmp::Ordering::Less))
-        .map(|(_, obj, isect)| (obj, isect))
+/// The media without the given interior
+fn without(media: &rpds::Stack<usize>, interior: usize) -> rpds::Stack<usize> {
+    let rest = media.iter().filter(|&&v| v != interior).collect::<Vec<_>>();
+    rest.into_iter().rev().fold(rpds::Stack::new(), |stack, &v| stack.push(v))
+}
+
+/// Medium the ray travels through: the volume it entered last, or the fog if it isn't inside of any
+fn medium_of<'a>(ray: &RayData, config: &'a RtConfig) -> Option<Medium<'a>> {
+    let volume = ray
+        .media
+        .iter()
+        .find_map(|&interior| config.interiors[interior].data.mat.volume.as_ref());
+    match volume {
+        Some(volume) => Some(Medium::Volume(volume)),
+        None => config.fog.as_ref().map(Medium::Fog),
+    }
+}
+
+/// How far rays that leave the scene are followed through a medium. Beyond twice the distance of the
+/// lights there is little of their light left to scatter
+fn escape_distance(ray: &RayData, config: &RtConfig) -> f32 {
+    config
+        .lights
+        .iter()
+        .map(|light| 2.0 * (light.pos.translation.vector - ray.ray.origin.coords).norm())
+        .fold(1.0, f32::max)
+}
+
+/// Light that the medium scatters towards the origin of the ray over the first `length` units
+/// of distance along it, and the fraction of the light from there that gets through
+fn through_medium(ray: &RayData, length: f32, medium: Medium, config: &RtConfig) -> (Color, Color) {
+    let origin = ray.ray.origin;
+    let dir = ray.ray.dir.normalize();
+    let through = medium.transmittance(origin, origin + dir * length);
+
+    // The ambient light comes from everywhere, so it doesn't need to be gathered
+    let mut scattered = medium.albedo() * config.ambient * through.map(|t| 1.0 - t);
+
+    // The points where light is gathered are offset differently for every ray, which turns the bands
+    // between the steps into noise. A point along the ray tells apart the rays through different pixels
+    let step = length / MEDIUM_STEPS as f32;
+    let offset = sampling::Rng::new(sampling::seed(ray.time, &(origin + dir))).next_f32();
+    for i in 0..MEDIUM_STEPS {
+        let point = origin + dir * ((i as f32 + offset) * step);
+        let towards_origin = medium.transmittance(origin, point) * medium.scattering(point) * step;
+
+        for light in &config.lights {
+            let light_pos = Point3::from(light.pos.translation.vector);
+            let light_brightness = match light.kind {
+                LightSourceKind::Point => light.brightness,
+            };
+            let falloff = na::distance_squared(&point, &light_pos).max(MIN_LIGHT_DISTANCE.powi(2));
+            let lit = light_brightness
+                * transmittance(point, light_pos, ray.time, &ray.media, config)
+                * (PHASE / falloff);
+            scattered = scattered + towards_origin * lit;
+        }
+    }
+
+    (scattered, through)
+}
+
+/// Fraction of the light at `to` that reaches `from`. Opaque objects that cast shadows block it, while
+/// volumes, fog and see-through surfaces let part of it through. `media` are the objects that `from` is inside of
+fn transmittance(
+    from: Point3<f32>,
+    to: Point3<f32>,
+    time: f32,
+    media: &rpds::Stack<usize>,
+    config: &RtConfig,
+) -> Color {
+    let ray = Ray::new(from, to - from);
+    let mut result = match &config.fog {
+        Some(fog) => Medium::Fog(fog).transmittance(from, to),
+        None => Color::white(),
+    };
+
+    for_each_interference(&ray, time, media, config, &seen_by(SHADOW_GROUP), |data, isect| {
+        if isect.toi >= 1.0 {
+            return;
+        }
+        let (index, volume) = match (data.interior, &data.mat.volume) {
+            (Some(index), Some(volume)) => (index, volume),
+            _ if !data.mat.is_opaque() => {
+                result = result * data.mat.transmission(isect.uvs);
+                return;
+            }
+            _ => {
+                result = Color::black();
+                return;
+            }
+        };
+
+        // Inside of the volume the hit is where the ray leaves it, otherwise it has to be found
+        let (enter, leave) = if media.iter().any(|&v| v == index) {
+            (0.0, isect.toi)
+        } else {
+            let margin = VOLUME_MARGIN / ray.dir.norm();
+            let inside = Ray::new(ray.point_at(isect.toi + margin), ray.dir);
+            let obj = &config.interiors[index];
+            let exit = obj
+                .shape
+                .as_ray_cast()
+                .and_then(|shape| shape.toi_with_ray(&obj.position(time), &inside, false));
+            match exit {
+                Some(exit) => (isect.toi, isect.toi + margin + exit),
+                None => return,
+            }
+        };
+        result = result * Medium::Volume(volume).transmittance(ray.point_at(enter), ray.point_at(leave.min(1.0)));
+    });
+
+    result
+}
+
+/// Light that enters the object, scatters inside of it and comes out at the point with the normal.
+/// Light takes the same paths in both directions, so it is found by random walks from the point into
+/// the object, which gather the light shining on the surface where they come out again
+fn subsurface_light(
+    ray: &RayData,
+    normal: &Ray<f32>,
+    interior: usize,
+    subsurface: &Subsurface,
+    config: &RtConfig,
+) -> Color {
+    let mut rng = sampling::Rng::new(sampling::seed(ray.time, &normal.origin));
+    let media = ray.media.push(interior);
+    let inward = -normal.dir.normalize();
+
+    let mut total = Color::black();
+    for _ in 0..SUBSURFACE_WALKS {
+        let mut walk = Ray::new(normal.origin + inward * VOLUME_MARGIN, rng.cosine_direction(&inward));
+        let mut throughput = Color::white();
+
+        for _ in 0..SUBSURFACE_BOUNCES {
+            let distance = -(1.0 - rng.next_f32()).ln() * subsurface.radius;
+            let groups = CollisionGroups::new();
+            match first_interference_where(&walk, ray.time, &media, config, &groups, |_| true) {
+                Some((data, int)) if int.toi < distance => {
+                    // Other objects inside of this one swallow the light
+                    if data.interior == Some(interior) {
+                        let outward = if int.normal.dot(&walk.dir) > 0.0 {
+                            int.normal
+                        } else {
+                            -int.normal
+                        };
+                        let exit = walk.point_at(int.toi) + outward * VOLUME_MARGIN;
+                        let object = &config.interiors[interior].data;
+                        let light = irradiance(exit, outward, object, ray.time, &ray.media, config);
+                        total = total + throughput * light;
+                    }
+                    break;
+                }
+                Some(_) => {
+                    walk = Ray::new(walk.point_at(distance), rng.unit_vector());
+                    throughput = throughput * subsurface.color;
+                }
+                // The walk slipped out of the object
+                None => break,
+            }
+        }
+    }
+
+    total * (SUBSURFACE_WALKS as f32).recip()
+}
+
+/// Fraction of the ambient light that reaches a point of a surface with the normal, past the objects
+/// that cast shadows. `seed` picks the directions of the rays
+fn ambient_visibility(
+    point: Point3<f32>,
+    normal: &Vector3<f32>,
+    time: f32,
+    media: &rpds::Stack<usize>,
+    seed: u64,
+    settings: &AmbientOcclusion,
+    config: &RtConfig,
+) -> f32 {
+    let mut rng = sampling::Rng::new(seed);
+    let normal = normal.normalize();
+    let origin = point + normal * 0.00001;
+    let groups = seen_by(SHADOW_GROUP);
+
+    settings.visibility(&normal, &mut rng, |dir| {
+        let ray = Ray::new(origin, dir * settings.distance);
+        first_interference_where(&ray, time, media, config, &groups, |data| data.mat.volume.is_none())
+            .map_or(false, |(_, int)| int.toi < 1.0)
+    })
+}
+
+/// Light from the environment reflected towards the origin of the ray by a surface with the normal.
+/// Directions are picked by how much light arrives from them, and the light from every direction is
+/// scaled so that an environment of the same color everywhere lights surfaces like the ambient light
+fn environment_light(
+    ray: &RayData,
+    normal: &Ray<f32>,
+    phong: &Phong,
+    environment: &Environment,
+    config: &RtConfig,
+) -> Color {
+    let mut rng = sampling::Rng::new(sampling::seed(ray.time, &normal.origin));
+    let normal_dir = normal.dir.normalize();
+    let viewer = -ray.ray.dir.normalize();
+    let origin = normal.origin + normal_dir * 0.00001;
+    // Past this distance the ray has left every object behind
+    let reach = (origin.coords.norm() + config.extent).max(1.0);
+
+    let mut total = Color::black();
+    for _ in 0..environment.samples {
+        let (dir, radiance, pdf) = match environment.sample(&mut rng) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let cos = normal_dir.dot(&dir);
+        if cos <= 0.0 {
+            continue;
+        }
+
+        let light = radiance
+            * transmittance(origin, origin + dir * reach, ray.time, &ray.media, config)
+            * (std::f32::consts::PI * pdf).recip();
+        let reflection = normal_dir * (2.0 * cos) - dir;
+        let diffuse = phong.diffuse * light * cos;
+        let specular = (phong.specular * light).combine(&phong.shininess, |spec, shine| {
+            spec * viewer.dot(&reflection).max(0.0).powf(shine)
+        });
+        total = total + diffuse + specular;
+    }
+
+    total * (environment.samples.max(1) as f32).recip()
+}
+
+/// Light from the light sources falling onto a point of the surface of the object
+fn irradiance(
+    point: Point3<f32>,
+    normal: Vector3<f32>,
+    object: &WorldData,
+    time: f32,
+    media: &rpds::Stack<usize>,
+    config: &RtConfig,
+) -> Color {
+    let mut total = Color::black();
+    for (index, light) in config.lights.iter().enumerate() {
+        if !config.lights(index, object) {
+            continue;
+        }
+        let light_pos = Point3::from(light.pos.translation.vector);
+        let light_dir = light_pos - point;
+        let cos = normal.normalize().dot(&light_dir.normalize());
+        if cos <= 0.0 {
+            continue;
+        }
+
+        let light_brightness = match light.kind {
+            LightSourceKind::Point => light.brightness,
+        };
+        total = total
+            + light_brightness
+                * transmittance(point, light_pos, time, media, config)
+                * (cos / light_dir.norm_squared());
+    }
+    total
+}
+
+/// Photons gathered at matte surfaces
+#[derive(Debug)]
+struct Photons {
+    /// Photons that came off mirrors
+    caustics: PhotonMap,
+    /// Photons that bounced off matte surfaces, if that light is gathered
+    indirect: Option<PhotonMap>,
+    radius: f32,
+}
+
+impl Photons {
+    fn irradiance(&self, point: Point3<f32>, normal: Vector3<f32>) -> Color {
+        let caustics = self.caustics.irradiance(point, normal, self.radius);
+        match &self.indirect {
+            Some(indirect) => caustics + indirect.irradiance(point, normal, self.radius),
+            None => caustics,
+        }
+    }
+}
+
+/// Photons traced for a scene, shared by the renderers of its copies so that they are only traced once.
+/// Copies can only differ in what the photons don't depend on, like the camera and the size of the image
+#[derive(Clone, Debug, Default)]
+pub struct PhotonCache(Arc<Mutex<Option<Arc<Photons>>>>);
+
+/// Sends photons out of the lights and stores them where they land on matte surfaces after bouncing
+/// off something. The photons see the scene in the middle of the exposure, and pass through volumes
+fn trace_photons(settings: &PhotonMapping, config: &RtConfig) -> Photons {
+    let per_light = settings.count as usize / config.lights.len().max(1);
+    let batches = (0..config.lights.len())
+        .flat_map(|light| (0..per_light).step_by(PHOTON_BATCH).map(move |first| (light, first)))
+        .collect::<Vec<_>>();
+    let trace = |&(light, first): &(usize, usize)| {
+        let count = PHOTON_BATCH.min(per_light - first);
+        trace_photon_batch(settings, config, light, first, count, per_light)
+    };
+
+    #[cfg(feature = "wasm")]
+    let traced = batches.iter().map(trace).collect::<Vec<_>>();
+    #[cfg(not(feature = "wasm"))]
+    let traced = batches.par_iter().map(trace).collect::<Vec<_>>();
+
+    let mut caustics = Vec::new();
+    let mut indirect = Vec::new();
+    for (batch_caustics, batch_indirect) in traced {
+        caustics.extend(batch_caustics);
+        indirect.extend(batch_indirect);
+    }
+
+    Photons {
+        caustics: PhotonMap::new(caustics),
+        indirect: if settings.indirect {
+            Some(PhotonMap::new(indirect))
+        } else {
+            None
+        },
+        radius: settings.radius,
+    }
+}
+
+/// Traces `count` of the `per_light` photons of the light with the given position in `lights`, starting
+/// from the photon `first`. Returns the photons that came off mirrors and the ones that bounced off matte
+/// surfaces. Every batch has its own random numbers, so the photons don't depend on the order of the batches
+fn trace_photon_batch(
+    settings: &PhotonMapping,
+    config: &RtConfig,
+    index: usize,
+    first: usize,
+    count: usize,
+    per_light: usize,
+) -> (Vec<Photon>, Vec<Photon>) {
+    let mut caustics = Vec::new();
+    let mut indirect = Vec::new();
+    let light = &config.lights[index];
+    let mut rng = sampling::Rng::new((index as u64) << 32 | first as u64);
+    let light_pos = Point3::from(light.pos.translation.vector);
+    let light_brightness = match light.kind {
+        LightSourceKind::Point => light.brightness,
+    };
+    // The brightness of a point light is the light it sends into every unit of solid angle
+    let power = light_brightness * (4.0 * std::f32::consts::PI / per_light as f32);
+
+    for _ in 0..count {
+        let mut ray = Ray::new(light_pos, rng.unit_vector());
+        let mut power = power;
+        let mut media = rpds::Stack::new();
+        let (mut mirrored, mut bounced) = (false, false);
+
+        for _ in 0..PHOTON_BOUNCES {
+            let groups = seen_by(SHADOW_GROUP);
+            let (data, int) = match first_interference_where(&ray, 0.5, &media, config, &groups, |_| true) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let point = ray.point_at(int.toi);
+
+            if let (Some(interior), true) = (data.interior, data.lets_through(&media)) {
+                media = crossed(&media, interior);
+                ray = Ray::new(point + ray.dir * VOLUME_MARGIN, ray.dir);
+                continue;
+            }
+
+            // See-through surfaces let the photon through as often as they let light through
+            if let (Some(interior), false) = (data.interior, data.mat.is_opaque()) {
+                let transmission = data.mat.transmission(int.uvs);
+                let chance = transmission.channels().iter().sum::<f32>() / 3.0;
+                if rng.next_f32() < chance {
+                    power = power * transmission * chance.recip();
+                    media = crossed(&media, interior);
+                    ray = Ray::new(point + ray.dir * VOLUME_MARGIN, ray.dir);
+                    continue;
+                }
+            }
+
+            let Material { phong, reflect, .. } = &data.mat;
+            let normal = if int.normal.dot(&ray.dir) < 0.0 {
+                int.normal
+            } else {
+                -int.normal
+            };
+            if phong.part > 0.0 && config.lights(index, data) {
+                let photon = Photon::new(point, ray.dir, power);
+                if bounced {
+                    indirect.push(photon);
+                } else if mirrored {
+                    caustics.push(photon);
+                }
+            }
+
+            // Russian roulette picks what happens to the photon, so that its power can stay the same
+            let albedo = phong.diffuse.channels().iter().sum::<f32>() / 3.0;
+            let choice = rng.next_f32();
+            if choice < reflect.part {
+                let dir = ray.dir - normal * (2.0 * ray.dir.dot(&normal));
+                ray = Ray::new(point + normal * 0.00001, dir);
+                mirrored = true;
+            } else if settings.indirect && albedo > 0.0 && choice < reflect.part + phong.part * albedo {
+                power = power * phong.diffuse * albedo.recip();
+                ray = Ray::new(point + normal * 0.00001, rng.cosine_direction(&normal));
+                bounced = true;
+            } else {
+                break;
+            }
+        }
+    }
+
+    (caustics, indirect)
 }
 
 #[derive(Clone, Copy)]
 struct GetColorArgs<'a> {
     mat: &'a Material,
     normal: Ray<f32>,
+    /// The object that was hit
+    object: &'a WorldData,
 }
 
-fn get_color(ray: RayData, config: &RtConfig, args: GetColorArgs) -> Color {
+fn get_color(ray: RayData, config: &RtConfig, args: GetColorArgs, mut hit: Option<&mut Hit>) -> Color {
     let GetColorArgs {
-        mat: Material { phong, reflect },
+        mat: Material {
+            phong,
+            reflect,
+            subsurface,
+            ..
+        },
         normal,
+        object,
     } = args;
 
-    let mut color = phong.ambient * config.ambient;
+    let occlusion = config.occlusion.as_ref().map(|settings| {
+        let seed = sampling::seed(ray.time, &normal.origin);
+        ambient_visibility(normal.origin, &normal.dir, ray.time, &ray.media, seed, settings, config)
+    });
+    let mut color = phong.ambient * config.ambient * occlusion.unwrap_or(1.0);
+    if let Some(hit) = hit.as_mut() {
+        hit.occlusion = occlusion;
+    }
 
     let origin_with_margin = normal.origin + normal.dir * 0.00001;
 
@@ -208,26 +1254,46 @@ fn get_color(ray: RayData, config: &RtConfig, args: GetColorArgs) -> Color {
         let rotation_to_normal = UnitQuaternion::rotation_between(&viewer, &normal.dir).unwrap();
         rotation_to_normal * rotation_to_normal * viewer
     };
-    let reflection_color = cast_ray(
-        ray.push(Ray::new(origin_with_margin, viewer_reflection)),
-        config,
-    );
+    let reflection_ray = Ray::new(origin_with_margin, viewer_reflection);
+    let mut reflection = hit.as_ref().map(|_| RayNode::new(reflection_ray));
+    let reflection_color = cast_ray(ray.reflect(reflection_ray), config, reflection.as_mut());
+    if let Some(hit) = hit.as_mut() {
+        hit.reflection = reflection.filter(|_| ray.steps_left > 1).map(Box::new);
+    }
 
-    for light in &config.lights {
+    for (index, light) in config.lights.iter().enumerate() {
+        if !config.lights(index, object) {
+            continue;
+        }
         let light_pos = Point3::from(light.pos.translation.vector);
         let distance = na::distance(&normal.origin, &light_pos);
 
-        let intersection = first_interference(
-            Ray::new(origin_with_margin, light_pos - origin_with_margin),
-            &config.world,
+        // Volumes and see-through surfaces let light through, which is taken into account below
+        let intersection = first_interference_where(
+            &Ray::new(origin_with_margin, light_pos - origin_with_margin),
+            ray.time,
+            &ray.media,
+            config,
+            &seen_by(SHADOW_GROUP),
+            |data| data.mat.volume.is_none() && data.mat.is_opaque(),
         );
 
-        let light_is_visible = if let Some((_, intersection)) = intersection {
+        let light_is_visible = if let Some((_, intersection)) = &intersection {
             intersection.toi > distance
         } else {
             true
         };
 
+        let mut shadow_ray = ShadowRay {
+            light: index,
+            occluder: None,
+            contribution: Color::black(),
+        };
+        if let (false, Some((data, intersection))) = (light_is_visible, &intersection) {
+            let occluder_distance = intersection.toi * (light_pos - origin_with_margin).norm();
+            shadow_ray.occluder = Some((data.index, occluder_distance));
+        }
+
         if light_is_visible {
             let light_brightness = match light.kind {
                 LightSourceKind::Point => light.brightness,
@@ -247,18 +1313,62 @@ fn get_color(ray: RayData, config: &RtConfig, args: GetColorArgs) -> Color {
                 spec * viewer.angle(&light_reflection).cos().max(0.0).powf(shine)
             });
 
-            let phong_color = (diffuse + specular) * na::distance_squared(&normal.origin, &light_pos).recip();
+            let mut phong_color = (diffuse + specular) * na::distance_squared(&normal.origin, &light_pos).recip();
+            if config.has_media() {
+                phong_color = phong_color
+                    * transmittance(origin_with_margin, light_pos, ray.time, &ray.media, config);
+            }
 
             color = color + phong_color * phong.part;
+            shadow_ray.contribution = phong_color * phong.part;
+        }
+
+        if let Some(hit) = hit.as_mut() {
+            hit.shadow_rays.push(shadow_ray);
         }
     }
 
+    if let Some(environment) = &config.environment {
+        color = color + environment_light(&ray, &normal, phong, environment, config) * phong.part;
+    }
+
+    if let Some(photons) = &config.photons {
+        color = color + phong.diffuse * photons.irradiance(normal.origin, normal.dir) * phong.part;
+    }
+
+    if let (Some(interior), true) = (object.interior, subsurface.part > 0.0) {
+        color = color + subsurface_light(&ray, &normal, interior, subsurface, config) * subsurface.part;
+    }
+
     color + reflection_color * reflect.part
 }
 
 #[cfg(test)]
 mod test {
-    use super::to_uv;
+    use super::{
+        ambient_visibility, interpolate, swept_aabb, to_uv, transmittance, LightSource, PhotonCache,
+        RaytraceObject, Renderer, Visibility, MAX_LINKED_LIGHTS,
+    };
+    use crate::{
+        environment::{Environment, EnvironmentMap},
+        fb::Color,
+        material::{Material, Phong, Reflect, Subsurface, Transparency},
+        media::Volume,
+        occlusion::AmbientOcclusion,
+        photons::PhotonMapping,
+    };
+    use na::{Isometry3, Point3, Vector3};
+    use nc::shape::{Ball, Cuboid, ShapeHandle};
+
+    fn object(pos: Isometry3<f32>, shape: ShapeHandle<f32>, mat: Material) -> RaytraceObject {
+        RaytraceObject {
+            pos,
+            motion: None,
+            shape,
+            mat,
+            visibility: Visibility::default(),
+        }
+    }
 
     #[test]
     fn center_left() {
@@ -274,4 +1384,332 @@ mod test {
     fn top_left_over_two() {
         assert_eq!(to_uv(10, 5, (40, 20)), (-1.0, 0.5))
     }
+
+    #[test]
+    fn inspect_records_hit() {
+        let ball = object(
+            Isometry3::translation(0.0, 0.0, 5.0),
+            ShapeHandle::new(Ball::new(1.0)),
+            Default::default(),
+        );
+        let light = LightSource::point(Color::white(), Point3::new(0.0, 0.0, -1.0));
+        let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), vec![ball], vec![light]);
+
+        let center = renderer.inspect(10.0, 10.0);
+        let hit = center.hit.expect("Center ray missed the ball");
+        assert_eq!(hit.object, 0);
+        assert!((hit.depth - 4.0).abs() < 1e-3);
+        assert!((hit.normal - -Vector3::z()).norm() < 1e-3);
+        assert_eq!(hit.shadow_rays.len(), 1);
+        assert!(hit.shadow_rays[0].occluder.is_none());
+
+        assert!(renderer.inspect(0.0, 0.0).hit.is_none());
+    }
+
+    #[test]
+    fn moving_objects_are_hit_where_they_are_at_the_time() {
+        let ball = RaytraceObject {
+            motion: Some(Isometry3::translation(3.0, 0.0, 5.0)),
+            ..object(
+                Isometry3::translation(-3.0, 0.0, 5.0),
+                ShapeHandle::new(Ball::new(1.0)),
+                Default::default(),
+            )
+        };
+        let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), vec![ball], Vec::new())
+            .with_motion(None, (0.25, 0.75));
+
+        // When the shutter opens the ball is still left of the middle of the image
+        assert_eq!(renderer.sample(10.0, 10.0, 0.0), renderer.config.ambient);
+        // Halfway through the exposure it is in the middle
+        let hit = renderer.inspect(10.0, 10.0).hit.expect("Ray missed the moving ball");
+        assert!(hit.point.x.abs() < 1e-4);
+    }
+
+    #[test]
+    fn volumes_absorb_light_passing_through() {
+        let smoke = object(
+            Isometry3::translation(0.0, 0.0, 5.0),
+            ShapeHandle::new(Ball::new(1.0)),
+            Material {
+                volume: Some(Volume {
+                    absorption: Color::new(0.5, 1.0, 0.0),
+                    scattering: Color::black(),
+                }),
+                ..Default::default()
+            },
+        );
+        let renderer = Renderer::new((21, 21), 90.0, 4, Isometry3::identity(), vec![smoke], Vec::new());
+
+        // The ray through the middle crosses 2 units of the volume
+        let expected = renderer.config.ambient * Color::new((-1.0_f32).exp(), (-2.0_f32).exp(), 1.0);
+        let color = renderer.sample(10.5, 10.5, 0.0);
+        for (c, e) in color.channels().iter().zip(&expected.channels()) {
+            assert!((c - e).abs() < 1e-4, "{:?} != {:?}", color, expected);
+        }
+        assert_eq!(renderer.sample(0.5, 0.5, 0.0), renderer.config.ambient);
+    }
+
+    #[test]
+    fn light_shines_through_translucent_objects() {
+        let render = |part: f32| {
+            let ball = object(
+                Isometry3::translation(0.0, 0.0, 5.0),
+                ShapeHandle::new(Ball::new(1.0)),
+                Material {
+                    subsurface: Subsurface {
+                        part,
+                        color: Color::white(),
+                        radius: 0.5,
+                    },
+                    ..Default::default()
+                },
+            );
+            // The light is behind the ball, so the side facing the camera is in its shadow
+            let light = LightSource::point(Color::white() * 10.0, Point3::new(0.0, 0.0, 7.5));
+            let renderer = Renderer::new((21, 21), 90.0, 2, Isometry3::identity(), vec![ball], vec![light]);
+            (0..16)
+                .map(|i| renderer.sample(10.5, 10.5, i as f32 / 16.0).channels()[0])
+                .sum::<f32>()
+        };
+
+        // The ambient light is blue, so red can only come from the light
+        assert_eq!(render(0.0), 0.0);
+        assert!(render(1.0) > 0.01);
+    }
+
+    #[test]
+    fn crossing_volumes_does_not_use_up_steps() {
+        let clear = |z: f32| {
+            object(
+                Isometry3::translation(0.0, 0.0, z),
+                ShapeHandle::new(Ball::new(1.0)),
+                Material {
+                    volume: Some(Volume {
+                        absorption: Color::black(),
+                        scattering: Color::black(),
+                    }),
+                    ..Default::default()
+                },
+            )
+        };
+        let objects = vec![clear(3.0), clear(6.0)];
+        let renderer = Renderer::new((21, 21), 90.0, 2, Isometry3::identity(), objects, Vec::new());
+
+        // Four crossings, but the background is still seen with only two steps
+        assert_eq!(renderer.sample(10.5, 10.5, 0.0), renderer.config.ambient);
+    }
+
+    #[test]
+    fn transparent_objects_cast_tinted_shadows() {
+        let pane = object(
+            Isometry3::translation(0.0, 0.0, 5.0),
+            ShapeHandle::new(Cuboid::new(Vector3::new(2.0, 2.0, 0.1))),
+            Material {
+                phong: Phong {
+                    part: 0.0,
+                    ambient: Color::black(),
+                    ..Default::default()
+                },
+                transparency: Transparency {
+                    part: 0.5,
+                    color: Color::new(1.0, 0.5, 0.5),
+                },
+                ..Default::default()
+            },
+        );
+        let renderer = Renderer::new((21, 21), 90.0, 4, Isometry3::identity(), vec![pane], Vec::new());
+        let tint = Color::new(0.5, 0.25, 0.25);
+
+        // The pane tints the light once on the way through it, from either side, and so do
+        // camera rays that enter and leave it
+        let (front, back) = (Point3::origin(), Point3::new(0.0, 0.0, 10.0));
+        let media = rpds::Stack::new();
+        assert_eq!(transmittance(front, back, 0.0, &media, &renderer.config), tint);
+        assert_eq!(transmittance(back, front, 0.0, &media, &renderer.config), tint);
+        assert_eq!(renderer.sample(10.5, 10.5, 0.0), renderer.config.ambient * tint);
+    }
+
+    #[test]
+    fn rays_only_see_the_objects_visible_to_them() {
+        let ball = |z: f32, visibility: Visibility| RaytraceObject {
+            visibility,
+            ..object(
+                Isometry3::translation(0.0, 0.0, z),
+                ShapeHandle::new(Ball::new(1.0)),
+                Default::default(),
+            )
+        };
+        let hidden = Visibility {
+            camera: false,
+            ..Default::default()
+        };
+        let shadowless = Visibility {
+            shadows: false,
+            ..Default::default()
+        };
+        let objects = vec![ball(5.0, hidden), ball(10.0, shadowless)];
+        let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), objects, Vec::new());
+
+        // The camera sees the second ball through the first one, but only the first one casts shadows
+        let hit = renderer.inspect(10.0, 10.0).hit.expect("Center ray missed the balls");
+        assert_eq!(hit.object, 1);
+        let media = rpds::Stack::new();
+        let through = |from: f32, to: f32| {
+            let (from, to) = (Point3::new(0.0, 0.0, from), Point3::new(0.0, 0.0, to));
+            transmittance(from, to, 0.0, &media, &renderer.config)
+        };
+        assert_eq!(through(0.0, 7.5), Color::black());
+        assert_eq!(through(7.5, 15.0), Color::white());
+    }
+
+    #[test]
+    fn swept_bounds_contain_turning_objects() {
+        use nc::bounding_volume::BoundingVolume;
+
+        let rod = ShapeHandle::new(Cuboid::new(Vector3::new(2.0, 0.1, 0.1)));
+        let start = Isometry3::new(Vector3::new(-1.0, 0.0, 0.0), Vector3::zeros());
+        let end = Isometry3::new(Vector3::new(1.0, 0.5, 0.0), Vector3::z() * 170.0_f32.to_radians());
+        let bounds = swept_aabb(&rod, &start, &end);
+
+        // Halfway through, the rod stands upright, above and below the boxes at both ends
+        for step in 0..=20 {
+            let pos = interpolate(&start, &end, step as f32 / 20.0);
+            assert!(bounds.contains(&rod.aabb(&pos)), "{}", step);
+        }
+    }
+
+    #[test]
+    fn linked_lights_only_shine_on_their_objects() {
+        let light = |objects: Option<Vec<usize>>| LightSource {
+            objects,
+            ..LightSource::point(Color::white(), Point3::new(0.0, 0.0, -1.0))
+        };
+        let render = |lights: Vec<LightSource>| {
+            let ball = object(
+                Isometry3::translation(0.0, 0.0, 5.0),
+                ShapeHandle::new(Ball::new(1.0)),
+                Default::default(),
+            );
+            let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), vec![ball], lights);
+            renderer.sample(10.0, 10.0, 0.0)
+        };
+
+        let everything = render(vec![light(None)]);
+        assert_eq!(render(vec![light(Some(vec![0]))]), everything);
+        // Without the light, the ball only reflects the ambient light
+        let ambient = Renderer::new((1, 1), 90.0, 1, Isometry3::identity(), Vec::new(), Vec::new())
+            .config
+            .ambient;
+        assert_eq!(render(vec![light(Some(Vec::new()))]), ambient);
+
+        // Lights up to the limit can be linked, and the ones after them shine on everything
+        let mut lights = (0..MAX_LINKED_LIGHTS).map(|_| light(Some(Vec::new()))).collect::<Vec<_>>();
+        lights.push(light(None));
+        assert_eq!(render(lights), everything);
+    }
+
+    #[test]
+    fn objects_occlude_the_ambient_light_nearby() {
+        let ball = object(
+            Isometry3::translation(0.0, 1.1, 0.0),
+            ShapeHandle::new(Ball::new(1.0)),
+            Default::default(),
+        );
+        let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), vec![ball], Vec::new())
+            .with_ambient_occlusion(Some(AmbientOcclusion {
+                samples: 64,
+                distance: 1.0,
+            }));
+        let settings = renderer.config.occlusion.as_ref().unwrap();
+        let media = rpds::Stack::new();
+        let visibility = |x: f32| {
+            let point = Point3::new(x, 0.0, 0.0);
+            ambient_visibility(point, &Vector3::y(), 0.5, &media, 1, settings, &renderer.config)
+        };
+
+        // Right below the ball most of the sky is covered, further away than `distance` none of it
+        assert!(visibility(0.0) < 0.5);
+        assert_eq!(visibility(3.0), 1.0);
+    }
+
+    #[test]
+    fn the_environment_lights_the_surfaces_it_can_see() {
+        let cuboid = |y: f32, camera: bool| RaytraceObject {
+            visibility: Visibility {
+                camera,
+                ..Default::default()
+            },
+            ..object(
+                Isometry3::translation(0.0, y, 5.0),
+                ShapeHandle::new(Cuboid::new(Vector3::new(10.0, 0.5, 10.0))),
+                Default::default(),
+            )
+        };
+        let environment = Environment {
+            image: "sky.hdr".into(),
+            rotation: 0.0,
+            intensity: 1.0,
+            samples: 2048,
+            map: Some(std::sync::Arc::new(EnvironmentMap::new(8, 4, vec![Color::white(); 32]))),
+        };
+        let render = |objects| {
+            let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), objects, Vec::new())
+                .with_environment(Some(environment.clone()));
+            let [floor, _, _] = renderer.sample(10.5, 15.5, 0.5).channels();
+            let [sky, _, _] = renderer.sample(10.5, 2.5, 0.5).channels();
+            (floor, sky)
+        };
+
+        // The sky is seen above the floor, and lights it like ambient light of the same color
+        let (floor, sky) = render(vec![cuboid(-1.5, true)]);
+        assert_eq!(sky, 1.0);
+        assert!((floor - 1.0).abs() < 0.1, "{}", floor);
+
+        // A roof that the camera doesn't see still keeps most of the sky from the floor
+        let (roofed, sky) = render(vec![cuboid(-1.5, true), cuboid(3.0, false)]);
+        assert_eq!(sky, 1.0);
+        assert!(roofed < 0.5, "{}", roofed);
+    }
+
+    #[test]
+    fn mirrors_focus_photons_into_shadows() {
+        let cuboid = |x: f32, y: f32, size: Vector3<f32>, mat: Material| {
+            object(Isometry3::translation(x, y, 0.0), ShapeHandle::new(Cuboid::new(size)), mat)
+        };
+        let mirror = Material {
+            reflect: Reflect { part: 1.0 },
+            ..Default::default()
+        };
+        let objects = || {
+            vec![
+                cuboid(0.0, -0.5, Vector3::new(10.0, 0.5, 10.0), Material::default()),
+                // Keeps the light from the middle of the floor
+                cuboid(0.0, 2.5, Vector3::new(1.0, 0.05, 1.0), Material::default()),
+                cuboid(4.05, 2.5, Vector3::new(0.05, 3.0, 3.0), mirror.clone()),
+            ]
+        };
+        let light = || LightSource::point(Color::white() * 10.0, Point3::new(0.0, 5.0, 0.0));
+        let settings = PhotonMapping {
+            count: 20_000,
+            radius: 1.0,
+            indirect: false,
+        };
+        let cache = PhotonCache::default();
+        let renderer = Renderer::new((20, 20), 90.0, 2, Isometry3::identity(), objects(), vec![light()])
+            .with_photon_mapping(Some(settings.clone()), &cache);
+
+        // The reflection of the light in the mirror shines on the middle of the floor from (8, 5, 0)
+        let distance = Vector3::new(8.0_f32, 5.0, 0.0).norm();
+        let expected = 10.0 * (5.0 / distance) / distance.powi(2);
+        let photons = renderer.config.photons.as_ref().unwrap();
+        let irradiance = photons.irradiance(Point3::origin(), Vector3::y()).channels()[0];
+        assert!((irradiance - expected).abs() < expected * 0.3, "{} != {}", irradiance, expected);
+
+        // Another view of the same scene reuses the photons
+        let camera = Isometry3::translation(0.0, 1.0, 5.0);
+        let other = Renderer::new((10, 10), 60.0, 2, camera, objects(), vec![light()])
+            .with_photon_mapping(Some(settings), &cache);
+        assert!(std::sync::Arc::ptr_eq(photons, other.config.photons.as_ref().unwrap()));
+    }
 }
@@ -7,12 +7,26 @@ extern crate ncollide3d as nc;
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
+use std::convert::TryFrom;
+
 use png::HasParameters;
 
+pub mod animation;
+pub mod environment;
 pub mod fb;
+pub mod inspect;
 pub mod material;
+pub mod media;
+pub mod occlusion;
+pub mod photons;
+pub mod progress;
+pub mod progressive;
 pub mod raytrace;
+pub mod sampling;
 pub mod scene;
+pub mod sdf;
+pub mod shapes;
+pub mod tiles;
 
 #[cfg(feature = "wasm")]
 #[wasm_bindgen]
@@ -31,38 +45,172 @@ pub fn trace_wasm(scene: &str) -> Result<js_sys::Uint8Array, JsValue> {
 }
 
 pub fn trace(scene: &str) -> Result<Vec<u8>, String> {
-    let fb = trace_scene(ron::de::from_str(scene).map_err(|e| format!("{:?}", e))?);
+    let scene: scene::Scene = ron::de::from_str(scene).map_err(|e| format!("{:?}", e))?;
+    scene.check()?;
+    let fb = trace_scene(scene);
     encode(fb).map_err(|e| format!("{:?}", e))
 }
 
+/// Traces the scene. If it has a crop window, only that part of the image is returned
 pub fn trace_scene(scene: scene::Scene) -> fb::Fb {
+    let multisample = scene.multisample;
+    let region = scene.region();
+
+    prepare_scene(scene).render_tile(region, multisample)
+}
+
+/// Traces the scene tile by tile in `scene.tile_order`, passing every finished tile to `callback`.
+/// Tiles only cover the crop window, if there is one, but their positions are relative to the whole image.
+/// Returning `false` from the callback cancels the rest of the render.
+/// Returns `true` if all the tiles were traced
+pub fn render_tiles(
+    scene: scene::Scene,
+    tile_size: u16,
+    mut callback: impl FnMut(tiles::Tile, fb::Fb) -> bool,
+) -> bool {
+    let multisample = scene.multisample;
+    let order = scene.tile_order;
+    let region = scene.region();
+    let renderer = prepare_scene(scene);
+
+    for tile in tiles::tiles_in(region, tile_size, order) {
+        if !callback(tile, renderer.render_tile(tile, multisample)) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Starts `render_tiles` on a background thread. The finished image, cropped like in `trace_scene`,
+/// is returned by `RenderHandle::wait`
+#[cfg(not(feature = "wasm"))]
+pub fn spawn_render(scene: scene::Scene, tile_size: u16) -> progress::RenderHandle {
+    let region = scene.region();
+
+    progress::RenderHandle::spawn(region.area(), move |progress, cancel, _| {
+        let mut fb = fb::Fb::new_empty(region.width, region.height);
+        let finished = render_tiles(scene, tile_size, |tile, tile_fb| {
+            fb.insert(tile.x - region.x, tile.y - region.y, &tile_fb);
+            progress.advance(tile.area());
+            !cancel.is_cancelled()
+        });
+
+        if finished {
+            Some(fb)
+        } else {
+            None
+        }
+    })
+}
+
+/// Starts rendering one of the passes of the scene on a background thread, instead of the shaded image.
+/// The pass is rendered in one go, so that the depth is scaled the same way across the image
+#[cfg(not(feature = "wasm"))]
+pub fn spawn_pass(scene: scene::Scene, pass: inspect::Aov) -> progress::RenderHandle {
+    let region = scene.region();
+
+    progress::RenderHandle::spawn(region.area(), move |progress, _, _| {
+        let fb = prepare_scene(scene).render_aov(region, pass);
+        progress.advance(region.area());
+        Some(fb)
+    })
+}
+
+/// Starts progressive refinement of the scene on a background thread, sending the image
+/// after every pass and at most every `refresh` while a pass is traced. Without `refresh`
+/// no images are sent, and only the finished one is returned by `RenderHandle::wait`
+#[cfg(not(feature = "wasm"))]
+pub fn spawn_progressive(
+    scene: scene::Scene,
+    passes: u32,
+    refresh: Option<std::time::Duration>,
+) -> progress::RenderHandle {
+    let total = scene.region().area() * passes as usize;
+
+    progress::RenderHandle::spawn(total, move |progress, cancel, images| {
+        let mut progressive = progressive::Progressive::new(scene);
+        let mut last_refresh = std::time::Instant::now();
+
+        while progressive.passes() < passes {
+            for bucket in progressive.buckets() {
+                if cancel.is_cancelled() {
+                    return None;
+                }
+
+                progressive.render_bucket(bucket);
+                progress.advance(bucket.area());
+
+                if refresh.map_or(false, |refresh| last_refresh.elapsed() > refresh) {
+                    let _ = images.send(progressive.image());
+                    last_refresh = std::time::Instant::now();
+                }
+            }
+            progressive.finish_pass();
+
+            if refresh.is_some() {
+                let _ = images.send(progressive.image());
+                last_refresh = std::time::Instant::now();
+            }
+        }
+
+        Some(progressive.image())
+    })
+}
+
+pub fn prepare_scene(scene: scene::Scene) -> raytrace::Renderer {
     let size = scene.size;
     let fov = scene.fov;
     let steps = scene.steps;
-    let multisample = scene.multisample;
+    let shutter = scene.shutter;
+    let camera_end = scene.camera_motion();
+    let fog = scene.fog.clone();
+    let photon_mapping = scene.photon_mapping.clone();
+    let photons = scene.photons.clone();
+    let ambient_occlusion = scene.ambient_occlusion.clone();
+    let environment = scene.environment.clone();
 
     let (camera, objects, lights) = scene.unpack();
 
-    const SAMPLES: u16 = 2;
-
-    if multisample {
-        let fb = raytrace::raytrace(
-            (size.0 * SAMPLES, size.1 * SAMPLES),
-            fov,
-            steps,
-            camera,
-            objects,
-            lights,
-        );
-        fb::Fb::from_func(size.0, size.1, |x, y| {
-            (0..SAMPLES)
-                .flat_map(|dx| (0..SAMPLES).map(move |dy| (x * SAMPLES + dx, y * SAMPLES + dy)))
-                .map(|(x, y)| fb.get(x, y) * (SAMPLES as f32).powi(2).recip())
-                .fold(fb::Color::black(), |acc, a| acc + a)
-        })
-    } else {
-        raytrace::raytrace(size, fov, steps, camera, objects, lights)
+    raytrace::Renderer::new(size, fov, steps, camera, objects, lights)
+        .with_motion(camera_end, shutter)
+        .with_fog(fog)
+        .with_photon_mapping(photon_mapping, &photons)
+        .with_ambient_occlusion(ambient_occlusion)
+        .with_environment(environment)
+}
+
+/// Decodes an 8-bit PNG image
+pub fn decode(png: &[u8]) -> Result<fb::Fb, String> {
+    let (info, mut reader) = png::Decoder::new(png)
+        .read_info()
+        .map_err(|e| format!("{}", e))?;
+    let (width, height) = match (u16::try_from(info.width), u16::try_from(info.height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(format!("Image is too large: {}x{}", info.width, info.height)),
+    };
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| format!("{}", e))?;
+
+    if info.bit_depth != png::BitDepth::Eight {
+        return Err(format!("Unsupported bit depth {:?}", info.bit_depth));
     }
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        other => return Err(format!("Unsupported color type {:?}", other)),
+    };
+
+    Ok(fb::Fb::from_func(width, height, |x, y| {
+        let i = (y as usize * info.line_size) + x as usize * channels;
+        if channels < 3 {
+            fb::Color::from_pixel([data[i]; 3])
+        } else {
+            fb::Color::from_pixel([data[i], data[i + 1], data[i + 2]])
+        }
+    }))
 }
 
 pub fn encode(fb: fb::Fb) -> std::io::Result<Vec<u8>> {
@@ -80,3 +228,10 @@ pub fn encode(fb: fb::Fb) -> std::io::Result<Vec<u8>> {
 
     Ok(res)
 }
+
+/// Encodes the image as a binary PPM (P6)
+pub fn encode_ppm(fb: fb::Fb) -> Vec<u8> {
+    let mut res = format!("P6\n{} {}\n255\n", fb.width(), fb.height()).into_bytes();
+    res.extend(fb.to_bytes());
+    res
+}
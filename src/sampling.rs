@@ -0,0 +1,108 @@
+//! Random numbers for everything that is sampled. Seeded explicitly, so renders are reproducible
+
+use na::{Point3, UnitQuaternion, Vector3};
+
+/// A small and fast generator (SplitMix64). Not suitable for anything but sampling
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniformly distributed in `[min, max)`
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+
+    /// Uniformly distributed over all directions
+    pub fn unit_vector(&mut self) -> Vector3<f32> {
+        let z = self.range(-1.0, 1.0);
+        let angle = self.range(0.0, std::f32::consts::PI * 2.0);
+        let r = (1.0 - z * z).sqrt();
+        Vector3::new(r * angle.cos(), r * angle.sin(), z)
+    }
+
+    /// A direction on the side of `normal`, more likely the closer it is to `normal`, like the light
+    /// leaving a matte surface
+    pub fn cosine_direction(&mut self, normal: &Vector3<f32>) -> Vector3<f32> {
+        let r = self.next_f32().sqrt();
+        let angle = self.range(0.0, std::f32::consts::PI * 2.0);
+        let local = Vector3::new(r * angle.cos(), r * angle.sin(), (1.0 - r * r).sqrt());
+
+        let rotation = UnitQuaternion::rotation_between(&Vector3::z(), normal)
+            .unwrap_or_else(|| UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f32::consts::PI));
+        rotation * local
+    }
+}
+
+/// A fixed pseudo-random number in `[0, 1)` for every pixel. Added to the samples of a pixel, it keeps
+/// neighbouring pixels from sampling the same points, which would show as patterns
+pub fn pixel_offset(x: u16, y: u16) -> f32 {
+    Rng::new(u64::from(x) << 16 | u64::from(y)).next_f32()
+}
+
+/// Seed for the samples taken at a point of a surface at a moment of the frame. Every coordinate is
+/// hashed on its own, so points next to each other or on the same diagonal get unrelated samples
+pub fn seed(time: f32, point: &Point3<f32>) -> u64 {
+    [time, point.x, point.y, point.z]
+        .iter()
+        .fold(0, |hash, value| Rng::new(hash ^ u64::from(value.to_bits())).next_u64())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{seed, Rng};
+    use na::{Point3, Vector3};
+
+    #[test]
+    fn floats_in_unit_interval() {
+        let mut rng = Rng::new(7);
+        let values = (0..10_000).map(|_| rng.next_f32()).collect::<Vec<_>>();
+        assert!(values.iter().all(|&v| (0.0..1.0).contains(&v)));
+
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!((mean - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn cosine_directions_face_the_normal() {
+        let mut rng = Rng::new(3);
+        let normal = Vector3::new(0.0, -1.0, 1.0).normalize();
+        let directions = (0..10_000).map(|_| rng.cosine_direction(&normal)).collect::<Vec<_>>();
+        assert!(directions.iter().all(|d| d.dot(&normal) >= 0.0));
+        assert!(directions.iter().all(|d| (d.norm() - 1.0).abs() < 1e-4));
+
+        // The average cosine of cosine distributed directions is 2/3
+        let mean = directions.iter().map(|d| d.dot(&normal)).sum::<f32>() / directions.len() as f32;
+        assert!((mean - 2.0 / 3.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn seeds_differ_for_points_with_the_same_coordinate_sum() {
+        let seeds = [
+            seed(0.5, &Point3::new(1.0, 2.0, 3.0)),
+            seed(0.5, &Point3::new(3.0, 2.0, 1.0)),
+            seed(0.5, &Point3::new(2.0, 2.0, 2.0)),
+            seed(0.25, &Point3::new(1.0, 2.0, 3.0)),
+        ];
+        for (i, a) in seeds.iter().enumerate() {
+            assert!(seeds[i + 1..].iter().all(|b| a != b));
+        }
+        assert_eq!(seeds[0], seed(0.5, &Point3::new(1.0, 2.0, 3.0)));
+    }
+}
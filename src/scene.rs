@@ -1,8 +1,28 @@
 //! Angles are specified in degrees
 
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::{fb::Color, material::Material, raytrace};
+use crate::{
+    animation::{self, Interpolation, Keyable},
+    environment::{Environment, EnvironmentMap},
+    fb::Color,
+    material::Material,
+    media::Fog,
+    occlusion::AmbientOcclusion,
+    photons::PhotonMapping,
+    raytrace,
+    sampling::Rng,
+    sdf::{Sdf, SdfShape},
+    shapes,
+    tiles::{Tile, TileOrder},
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum Rotation {
@@ -38,6 +58,17 @@ impl Rotation {
     }
 }
 
+impl Rotation {
+    fn from_raytrace(rot: na::UnitQuaternion<f32>) -> Self {
+        let (pitch, yaw, roll) = rot.euler_angles();
+        Rotation::Euler {
+            roll: roll.to_degrees(),
+            pitch: pitch.to_degrees(),
+            yaw: yaw.to_degrees(),
+        }
+    }
+}
+
 impl Default for Rotation {
     fn default() -> Self {
         Rotation::Euler {
@@ -76,12 +107,77 @@ impl Position {
     fn into_raytrace(self) -> na::Isometry3<f32> {
         na::Isometry3::from_parts(self.trans.into_raytrace(), self.rot.into_raytrace())
     }
+
+    fn from_raytrace(pos: na::Isometry3<f32>) -> Self {
+        let trans = pos.translation.vector;
+        Position {
+            trans: Translation {
+                x: trans.x,
+                y: trans.y,
+                z: trans.z,
+            },
+            rot: Rotation::from_raytrace(pos.rotation),
+        }
+    }
+}
+
+impl Keyable for Position {
+    /// Rotations are blended as quaternions, which is close to the shortest path for rotations
+    /// that aren't too far apart
+    fn blend(values: &[(f32, &Self)]) -> Self {
+        let isometries = values
+            .iter()
+            .map(|&(weight, pos)| (weight, pos.clone().into_raytrace()))
+            .collect::<Vec<_>>();
+        let first = match isometries.first() {
+            Some((_, first)) => first.rotation,
+            None => return Position::default(),
+        };
+
+        let mut translation = na::Vector3::zeros();
+        let mut rotation = na::Quaternion::new(0.0, 0.0, 0.0, 0.0);
+        for (weight, iso) in &isometries {
+            translation += iso.translation.vector * *weight;
+            // q and -q are the same rotation, the one on the side of the first rotation is blended
+            let q = iso.rotation.into_inner();
+            let q = if q.dot(&first.into_inner()) < 0.0 { -q } else { q };
+            rotation += q * *weight;
+        }
+
+        Position::from_raytrace(na::Isometry3::from_parts(
+            translation.into(),
+            na::UnitQuaternion::new_normalize(rotation),
+        ))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum Shape {
     Ball(f32),
     Cuboid { x: f32, y: f32, z: f32 },
+    Union(Vec<Part>),
+    Intersection(Vec<Part>),
+    /// The first part with all the others cut out of it
+    Difference(Vec<Part>),
+    /// Surface of a signed distance function, traced inside of a cube with the half size `bounds`
+    Sdf { bounds: f32, sdf: Sdf },
+    /// Terrain from a grayscale PNG, whose path is relative to the file that contains the shape.
+    /// Columns of the image go along x and rows along z, and `scale` is the size of the terrain,
+    /// with `y` the height of white. Only read by `Scene::load`. It has no inside, so it can't be
+    /// a part of other shapes
+    Heightfield {
+        image: PathBuf,
+        scale: Translation,
+        #[serde(skip)]
+        heights: Option<Arc<na::DMatrix<f32>>>,
+    },
+    /// Metaballs with the given centers and radii that melt together. Blobs reach further with
+    /// a lower threshold, between 0 and 1
+    Blobs {
+        centers: Vec<Translation>,
+        radii: Vec<f32>,
+        threshold: f32,
+    },
 }
 
 impl Shape {
@@ -91,43 +187,435 @@ impl Shape {
             Shape::Cuboid { x, y, z } => {
                 nc::shape::ShapeHandle::new(nc::shape::Cuboid::new(na::Vector3::new(x, y, z)))
             }
+            Shape::Union(parts) => csg(shapes::CsgOperation::Union, parts),
+            Shape::Intersection(parts) => csg(shapes::CsgOperation::Intersection, parts),
+            Shape::Difference(parts) => csg(shapes::CsgOperation::Difference, parts),
+            Shape::Sdf { bounds, sdf } => nc::shape::ShapeHandle::new(SdfShape::new(sdf, bounds)),
+            Shape::Blobs {
+                centers,
+                radii,
+                threshold,
+            } => {
+                let blobs = centers
+                    .into_iter()
+                    .map(|center| na::Point3::new(center.x, center.y, center.z))
+                    .zip(radii)
+                    .collect();
+                nc::shape::ShapeHandle::new(shapes::Blobs::new(blobs, threshold))
+            }
+            Shape::Heightfield { scale, heights, .. } => match heights {
+                Some(heights) => nc::shape::ShapeHandle::new(shapes::Heightfield::new(
+                    (*heights).clone(),
+                    scale.into_raytrace().vector,
+                )),
+                // Not read, which `check` reports
+                None => csg(shapes::CsgOperation::Union, Vec::new()),
+            },
+        }
+    }
+
+    /// Calls `f` for this shape and the shapes of all its parts
+    fn visit_mut(&mut self, f: &mut impl FnMut(&mut Shape) -> Result<(), String>) -> Result<(), String> {
+        if let Shape::Union(parts) | Shape::Intersection(parts) | Shape::Difference(parts) = self {
+            for part in parts {
+                part.shape.visit_mut(f)?;
+            }
+        }
+        f(self)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Shape::Union(parts) | Shape::Intersection(parts) | Shape::Difference(parts) => {
+                parts.iter().try_for_each(|part| match part.shape {
+                    Shape::Heightfield { .. } => {
+                        Err("Heightfields have no inside, so they can't be parts of other shapes".to_string())
+                    }
+                    _ => part.shape.check(),
+                })
+            }
+            Shape::Blobs { centers, radii, .. } if centers.len() != radii.len() => Err(format!(
+                "Blobs have {} centers but {} radii",
+                centers.len(),
+                radii.len()
+            )),
+            Shape::Blobs { radii, threshold, .. } => {
+                if let Some(radius) = radii.iter().find(|&&radius| radius <= 0.0 || radius.is_nan()) {
+                    return Err(format!("Blob radii must be positive, but one of them is {}", radius));
+                }
+                if !(0.0 < *threshold && *threshold < 1.0) {
+                    return Err(format!(
+                        "Blob threshold must be between 0 and 1, but it is {}",
+                        threshold
+                    ));
+                }
+                Ok(())
+            }
+            Shape::Heightfield {
+                image,
+                heights: None,
+                ..
+            } => Err(format!(
+                "Heightfield image {} is only read by `Scene::load`",
+                image.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A shape combined with others by constructive solid geometry
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Part {
+    /// Relative to the object
+    #[serde(default)]
+    pos: Position,
+    #[serde(default)]
+    scale: Scale,
+    shape: Shape,
+}
+
+fn csg(operation: shapes::CsgOperation, parts: Vec<Part>) -> nc::shape::ShapeHandle<f32> {
+    let parts = parts
+        .into_iter()
+        .map(|part| {
+            let linear = part.scale.into_raytrace();
+            let mut shape = part.shape.into_raytrace();
+            if linear != na::Matrix3::identity() {
+                // A part scaled to nothing is empty
+                shape = match shapes::Transformed::new(shape, linear) {
+                    Some(transformed) => nc::shape::ShapeHandle::new(transformed),
+                    None => nc::shape::ShapeHandle::new(shapes::Csg::new(
+                        shapes::CsgOperation::Union,
+                        Vec::new(),
+                    )),
+                };
+            }
+            (part.pos.into_raytrace(), shape)
+        })
+        .collect();
+
+    nc::shape::ShapeHandle::new(shapes::Csg::new(operation, parts))
+}
+
+/// A material written out in the object, or the name of one from the `materials` of the scene
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum MaterialRef {
+    Named(String),
+    Inline(Material),
+}
+
+impl Default for MaterialRef {
+    fn default() -> Self {
+        MaterialRef::Inline(Material::default())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum Scale {
+    Uniform(f32),
+    Axes {
+        #[serde(default = "default_scale")]
+        x: f32,
+        #[serde(default = "default_scale")]
+        y: f32,
+        #[serde(default = "default_scale")]
+        z: f32,
+    },
+}
+
+impl Scale {
+    fn into_raytrace(self) -> na::Matrix3<f32> {
+        match self {
+            Scale::Uniform(scale) => na::Matrix3::from_diagonal_element(scale),
+            Scale::Axes { x, y, z } => na::Matrix3::from_diagonal(&na::Vector3::new(x, y, z)),
+        }
+    }
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale::Uniform(1.0)
+    }
+}
+
+/// Placement of an object in a group: a linear map in the local space of the object,
+/// followed by an isometry
+#[derive(Clone, Copy)]
+struct Transform {
+    iso: na::Isometry3<f32>,
+    linear: na::Matrix3<f32>,
+}
+
+impl Transform {
+    fn identity() -> Self {
+        Transform {
+            iso: na::Isometry3::identity(),
+            linear: na::Matrix3::identity(),
+        }
+    }
+
+    fn new(pos: &Position, scale: &Scale) -> Self {
+        Transform {
+            iso: pos.clone().into_raytrace(),
+            linear: scale.clone().into_raytrace(),
+        }
+    }
+
+    /// Places `child`, which is relative to `self`, in the space `self` is relative to
+    fn then(&self, child: Transform) -> Transform {
+        let translation = self.iso * na::Point3::from(self.linear * child.iso.translation.vector);
+        let rotation = self.iso.rotation * child.iso.rotation;
+
+        // The parent's linear map is moved behind the child's rotation, so that the rotation stays
+        // part of the isometry. Without scaling the map stays exactly the identity
+        let linear = if self.linear == na::Matrix3::identity() {
+            child.linear
+        } else {
+            let child_rotation = child.iso.rotation.to_rotation_matrix();
+            child_rotation.inverse().matrix() * self.linear * child_rotation.matrix() * child.linear
+        };
+
+        Transform {
+            iso: na::Isometry3::from_parts(translation.coords.into(), rotation),
+            linear,
+        }
+    }
+}
+
+/// Transforms at the start and the end of the frame. `end` is `None` if nothing moves
+#[derive(Clone, Copy)]
+struct Placement {
+    start: Transform,
+    end: Option<Transform>,
+}
+
+impl Placement {
+    fn fixed(transform: Transform) -> Self {
+        Placement {
+            start: transform,
+            end: None,
+        }
+    }
+
+    fn new(pos: &Position, motion: &Option<Position>, scale: &Scale) -> Self {
+        Placement {
+            start: Transform::new(pos, scale),
+            end: motion.as_ref().map(|motion| Transform::new(motion, scale)),
+        }
+    }
+
+    /// Places `child`, which is relative to `self`, in the space `self` is relative to
+    fn then(&self, child: Placement) -> Placement {
+        let end = match (self.end, child.end) {
+            (None, None) => None,
+            (end, child_end) => Some(end.unwrap_or(self.start).then(child_end.unwrap_or(child.start))),
+        };
+        Placement {
+            start: self.start.then(child.start),
+            end,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Object {
+    /// Lets animation tracks refer to the object
+    #[serde(default)]
+    name: Option<String>,
     pos: Position,
+    /// Where the object is at the end of the frame, if it moves. Like `pos`, relative to the parent
+    #[serde(default)]
+    motion: Option<Position>,
+    /// Applied in the local space of the object, before `pos`
+    #[serde(default)]
+    scale: Scale,
     shape: Shape,
     #[serde(default)]
+    mat: MaterialRef,
+    #[serde(default = "visible")]
+    visible_to_camera: bool,
+    /// Whether the object blocks the light of the lights
+    #[serde(default = "visible")]
+    casts_shadows: bool,
+    #[serde(default = "visible")]
+    visible_in_reflections: bool,
+}
+
+fn visible() -> bool {
+    true
+}
+
+/// An object placed in the scene, with its transform not yet applied to the shape.
+/// Instances of a prototype share its shapes
+#[derive(Clone)]
+struct Placed {
+    transform: Placement,
+    shape: nc::shape::ShapeHandle<f32>,
     mat: Material,
+    visibility: raytrace::Visibility,
+    /// Names of the object and of the groups and instances it is in, which lights can be linked to
+    names: Vec<String>,
 }
 
-impl Object {
-    fn into_raytrace(self) -> raytrace::RaytraceObject {
-        raytrace::RaytraceObject {
-            pos: self.pos.into_raytrace(),
-            shape: self.shape.into_raytrace(),
+impl Placed {
+    /// Objects scaled to nothing along an axis are invisible and return `None`
+    fn into_raytrace(self) -> Option<raytrace::RaytraceObject> {
+        // Only the isometry moves. The linear map would only change during the frame for objects
+        // that rotate inside of a group that is scaled differently along its axes
+        let Placement { start, end } = self.transform;
+        let mut shape = self.shape;
+        if start.linear != na::Matrix3::identity() {
+            shape = nc::shape::ShapeHandle::new(shapes::Transformed::new(shape, start.linear)?);
+        }
+
+        Some(raytrace::RaytraceObject {
+            pos: start.iso,
+            motion: end.map(|end| end.iso),
+            shape,
             mat: self.mat,
+            visibility: self.visibility,
+        })
+    }
+}
+
+/// Copies of one of the `prototypes` of the scene
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Instance {
+    #[serde(default)]
+    name: Option<String>,
+    of: String,
+    #[serde(default)]
+    pos: Position,
+    #[serde(default)]
+    motion: Option<Position>,
+    #[serde(default)]
+    scale: Scale,
+    /// Replaces the materials of all the objects of the prototype
+    #[serde(default)]
+    mat: Option<MaterialRef>,
+    #[serde(default)]
+    layout: Layout,
+}
+
+/// Where the copies of an instance go, relative to the instance
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum Layout {
+    Single,
+    /// `count` copies, each one moved by `step` from the previous one
+    Array { count: u32, step: Position },
+    /// Copies on a grid with `counts` cells along the x, y and z axes, `spacing` apart
+    Grid {
+        counts: (u32, u32, u32),
+        spacing: Translation,
+    },
+    /// `count` copies at random points of a box of the given size centered on the instance,
+    /// turned randomly around the y axis and scaled by a random factor between `scale.0` and `scale.1`
+    Scatter {
+        count: u32,
+        size: Translation,
+        #[serde(default)]
+        seed: u64,
+        #[serde(default = "default_scatter_scale")]
+        scale: (f32, f32),
+    },
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Single
+    }
+}
+
+impl Layout {
+    fn transforms(&self) -> Vec<Transform> {
+        let at = |iso| Transform {
+            iso,
+            linear: na::Matrix3::identity(),
+        };
+
+        match self {
+            Layout::Single => vec![Transform::identity()],
+            Layout::Array { count, step } => {
+                let step = step.clone().into_raytrace();
+                let mut iso = na::Isometry3::identity();
+                (0..*count)
+                    .map(|_| {
+                        let copy = at(iso);
+                        iso *= step;
+                        copy
+                    })
+                    .collect()
+            }
+            Layout::Grid { counts, spacing } => {
+                let mut copies = Vec::new();
+                for z in 0..counts.2 {
+                    for y in 0..counts.1 {
+                        for x in 0..counts.0 {
+                            copies.push(at(na::Isometry3::translation(
+                                x as f32 * spacing.x,
+                                y as f32 * spacing.y,
+                                z as f32 * spacing.z,
+                            )));
+                        }
+                    }
+                }
+                copies
+            }
+            Layout::Scatter {
+                count,
+                size,
+                seed,
+                scale,
+            } => {
+                let mut rng = Rng::new(*seed);
+                (0..*count)
+                    .map(|_| {
+                        let translation = na::Vector3::new(
+                            rng.range(-0.5, 0.5) * size.x,
+                            rng.range(-0.5, 0.5) * size.y,
+                            rng.range(-0.5, 0.5) * size.z,
+                        );
+                        let yaw = rng.range(0.0, std::f32::consts::PI * 2.0);
+                        Transform {
+                            iso: na::Isometry3::new(translation, na::Vector3::y() * yaw),
+                            linear: na::Matrix3::from_diagonal_element(rng.range(scale.0, scale.1)),
+                        }
+                    })
+                    .collect()
+            }
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct LightSource {
+    /// Lets animation tracks refer to the light
+    #[serde(default)]
+    name: Option<String>,
     pos: Position,
     brightness: Color,
     kind: LightSourceKind,
+    /// Names of the objects, groups or instances that the light shines on. It shines on everything if `None`
+    #[serde(default)]
+    objects: Option<Vec<String>>,
 }
 
 impl LightSource {
-    fn into_raytrace(self) -> raytrace::LightSource {
+    /// `objects` are the names of the objects of the scene, in the order they are passed to the renderer
+    fn into_raytrace(self, objects: &[Vec<String>]) -> raytrace::LightSource {
         raytrace::LightSource {
             pos: self.pos.into_raytrace(),
             brightness: self.brightness,
             kind: match self.kind {
                 LightSourceKind::Point => raytrace::LightSourceKind::Point,
             },
+            objects: self.objects.map(|linked| {
+                (0..objects.len())
+                    .filter(|&index| objects[index].iter().any(|name| linked.contains(name)))
+                    .collect()
+            }),
         }
     }
 }
@@ -137,7 +625,614 @@ enum LightSourceKind {
     Point,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Entry of the object list: an object, a group of nodes that move together, or copies of a prototype.
+/// The kind of node is told by its fields, so groups and instances can be written as
+/// `Group(pos: ..., children: [...])` and `Instance(of: ..., pos: ...)`
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+enum Node {
+    Object(Object),
+    Group(Group),
+    Instance(Instance),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Group {
+    #[serde(default)]
+    name: Option<String>,
+    /// Position of the group. Positions of the children are relative to it
+    #[serde(default)]
+    pos: Position,
+    #[serde(default)]
+    motion: Option<Position>,
+    /// Scales the group as a whole, around its position
+    #[serde(default)]
+    scale: Scale,
+    children: Vec<Node>,
+}
+
+// Written by hand, because serde's untagged enums lose the variant names of the shapes
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::{self, MapAccess, Visitor};
+
+        #[derive(Deserialize)]
+        #[serde(field_identifier, rename_all = "lowercase")]
+        enum Field {
+            Name,
+            Pos,
+            Motion,
+            Scale,
+            Shape,
+            Mat,
+            Children,
+            Of,
+            Layout,
+            #[serde(rename = "visible_to_camera")]
+            VisibleToCamera,
+            #[serde(rename = "casts_shadows")]
+            CastsShadows,
+            #[serde(rename = "visible_in_reflections")]
+            VisibleInReflections,
+        }
+
+        struct NodeVisitor;
+
+        impl<'de> Visitor<'de> for NodeVisitor {
+            type Value = Node;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an object, a group or an instance")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node, A::Error> {
+                let (mut name, mut pos, mut motion, mut scale) = (None, None, None, None);
+                let (mut shape, mut mat) = (None, None);
+                let (mut children, mut of, mut layout) = (None, None, None);
+                let mut visibility = [None; 3];
+                while let Some(field) = map.next_key()? {
+                    match field {
+                        Field::Name => name = Some(map.next_value()?),
+                        Field::Pos => pos = Some(map.next_value()?),
+                        Field::Motion => motion = Some(map.next_value()?),
+                        Field::Scale => scale = Some(map.next_value()?),
+                        Field::Shape => shape = Some(map.next_value()?),
+                        Field::Mat => mat = Some(map.next_value()?),
+                        Field::Children => children = Some(map.next_value()?),
+                        Field::Of => of = Some(map.next_value()?),
+                        Field::Layout => layout = Some(map.next_value()?),
+                        Field::VisibleToCamera => visibility[0] = Some(map.next_value()?),
+                        Field::CastsShadows => visibility[1] = Some(map.next_value()?),
+                        Field::VisibleInReflections => visibility[2] = Some(map.next_value()?),
+                    }
+                }
+
+                if shape.is_none() && visibility.iter().any(Option::is_some) {
+                    return Err(de::Error::custom("only objects can be hidden from some of the rays"));
+                }
+                let [visible_to_camera, casts_shadows, visible_in_reflections] =
+                    visibility.map(|visible| visible.unwrap_or(true));
+
+                if let Some(of) = of {
+                    if shape.is_some() || children.is_some() {
+                        return Err(de::Error::custom(
+                            "instances can't have a shape or children of their own",
+                        ));
+                    }
+                    return Ok(Node::Instance(Instance {
+                        name,
+                        of,
+                        pos: pos.unwrap_or_default(),
+                        motion,
+                        scale: scale.unwrap_or_default(),
+                        mat,
+                        layout: layout.unwrap_or_default(),
+                    }));
+                }
+                if layout.is_some() {
+                    return Err(de::Error::custom("only instances can have a layout"));
+                }
+
+                match (shape, children) {
+                    (Some(shape), None) => Ok(Node::Object(Object {
+                        name,
+                        pos: pos.ok_or_else(|| de::Error::missing_field("pos"))?,
+                        motion,
+                        scale: scale.unwrap_or_default(),
+                        shape,
+                        mat: mat.unwrap_or_default(),
+                        visible_to_camera,
+                        casts_shadows,
+                        visible_in_reflections,
+                    })),
+                    (None, Some(children)) if mat.is_none() => Ok(Node::Group(Group {
+                        name,
+                        pos: pos.unwrap_or_default(),
+                        motion,
+                        scale: scale.unwrap_or_default(),
+                        children,
+                    })),
+                    (None, Some(_)) => Err(de::Error::custom("groups can't have a material")),
+                    (Some(_), Some(_)) => Err(de::Error::custom(
+                        "a node is either an object with a shape or a group with children",
+                    )),
+                    (None, None) => Err(de::Error::missing_field("shape")),
+                }
+            }
+        }
+
+        // Unlike `deserialize_struct`, this accepts any struct name
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+impl Node {
+    /// Calls `f` for this node and all the nodes in it. Doesn't follow instances
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Node)) {
+        f(self);
+        if let Node::Group(group) = self {
+            for child in &group.children {
+                child.visit(f);
+            }
+        }
+    }
+
+    /// Like `visit`, but the nodes can be changed
+    fn visit_mut(&mut self, f: &mut impl FnMut(&mut Node)) {
+        f(self);
+        if let Node::Group(group) = self {
+            for child in &mut group.children {
+                child.visit_mut(f);
+            }
+        }
+    }
+
+    fn name(&self) -> Option<&str> {
+        match self {
+            Node::Object(Object { name, .. })
+            | Node::Group(Group { name, .. })
+            | Node::Instance(Instance { name, .. }) => name.as_deref(),
+        }
+    }
+
+    /// Position of the node at the start of the frame and at its end
+    fn placement_mut(&mut self) -> (&mut Position, &mut Option<Position>) {
+        match self {
+            Node::Object(Object { pos, motion, .. })
+            | Node::Group(Group { pos, motion, .. })
+            | Node::Instance(Instance { pos, motion, .. }) => (pos, motion),
+        }
+    }
+
+    /// Calls `f` for the shapes of this node and all the nodes in it. Doesn't follow instances
+    fn visit_shapes_mut(&mut self, f: &mut impl FnMut(&mut Shape) -> Result<(), String>) -> Result<(), String> {
+        match self {
+            Node::Object(object) => object.shape.visit_mut(f),
+            Node::Group(group) => group
+                .children
+                .iter_mut()
+                .try_for_each(|child| child.visit_shapes_mut(f)),
+            Node::Instance(_) => Ok(()),
+        }
+    }
+
+    /// Calls `f` for the materials written out in the node and its children
+    fn visit_materials_mut(
+        &mut self,
+        f: &mut impl FnMut(&mut Material) -> Result<(), String>,
+    ) -> Result<(), String> {
+        match self {
+            Node::Object(Object {
+                mat: MaterialRef::Inline(mat),
+                ..
+            })
+            | Node::Instance(Instance {
+                mat: Some(MaterialRef::Inline(mat)),
+                ..
+            }) => f(mat),
+            Node::Group(group) => group
+                .children
+                .iter_mut()
+                .try_for_each(|child| child.visit_materials_mut(f)),
+            Node::Object(_) | Node::Instance(_) => Ok(()),
+        }
+    }
+}
+
+/// Turns nodes into placed objects. Prototypes are only turned into objects once,
+/// and all their instances share those objects' shapes
+struct Flattener<'a> {
+    materials: &'a HashMap<String, Material>,
+    prototypes: &'a HashMap<String, Node>,
+    flattened: HashMap<&'a str, Rc<Vec<Placed>>>,
+}
+
+impl<'a> Flattener<'a> {
+    fn new(
+        materials: &'a HashMap<String, Material>,
+        prototypes: &'a HashMap<String, Node>,
+    ) -> Self {
+        Flattener {
+            materials,
+            prototypes,
+            flattened: HashMap::new(),
+        }
+    }
+
+    /// Adds the objects of the node to `objects`, placing them relative to `parent`.
+    /// `names` are the names of the groups and instances the node is in
+    fn flatten(&mut self, node: &'a Node, parent: Placement, names: &[String], objects: &mut Vec<Placed>) {
+        let mut names = names.to_vec();
+        names.extend(node.name().map(str::to_string));
+
+        match node {
+            Node::Object(object) => objects.push(Placed {
+                transform: parent.then(Placement::new(&object.pos, &object.motion, &object.scale)),
+                shape: object.shape.clone().into_raytrace(),
+                mat: self.material(&object.mat),
+                visibility: raytrace::Visibility {
+                    camera: object.visible_to_camera,
+                    shadows: object.casts_shadows,
+                    reflections: object.visible_in_reflections,
+                },
+                names,
+            }),
+            Node::Group(group) => {
+                let transform = parent.then(Placement::new(&group.pos, &group.motion, &group.scale));
+                for child in &group.children {
+                    self.flatten(child, transform, &names, objects);
+                }
+            }
+            Node::Instance(instance) => {
+                let prototype = self.prototype(&instance.of);
+                let mat = instance.mat.as_ref().map(|mat| self.material(mat));
+                let transform =
+                    parent.then(Placement::new(&instance.pos, &instance.motion, &instance.scale));
+
+                for copy in instance.layout.transforms() {
+                    let copy = transform.then(Placement::fixed(copy));
+                    objects.extend(prototype.iter().map(|placed| Placed {
+                        transform: copy.then(placed.transform),
+                        shape: placed.shape.clone(),
+                        mat: mat.clone().unwrap_or_else(|| placed.mat.clone()),
+                        visibility: placed.visibility,
+                        names: names.iter().chain(&placed.names).cloned().collect(),
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Objects of the prototype, relative to the prototype
+    fn prototype(&mut self, name: &'a str) -> Rc<Vec<Placed>> {
+        if let Some(objects) = self.flattened.get(name) {
+            return Rc::clone(objects);
+        }
+
+        // Names and cycles are checked when the scene is loaded. The placeholder
+        // makes a prototype that contains itself empty instead of recursing forever
+        self.flattened.insert(name, Rc::new(Vec::new()));
+        let mut objects = Vec::new();
+        if let Some(node) = self.prototypes.get(name) {
+            self.flatten(node, Placement::fixed(Transform::identity()), &[], &mut objects);
+        }
+
+        let objects = Rc::new(objects);
+        self.flattened.insert(name, Rc::clone(&objects));
+        objects
+    }
+
+    fn material(&self, mat: &MaterialRef) -> Material {
+        match mat {
+            MaterialRef::Named(name) => self.materials.get(name).cloned().unwrap_or_default(),
+            MaterialRef::Inline(mat) => mat.clone(),
+        }
+    }
+}
+
+/// Contents of a file included by a scene
+#[derive(Deserialize, Default)]
+struct Library {
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    materials: HashMap<String, Material>,
+    #[serde(default)]
+    prototypes: HashMap<String, Node>,
+    #[serde(default)]
+    objects: Vec<Node>,
+    #[serde(default)]
+    lights: Vec<LightSource>,
+}
+
+impl Library {
+    /// Adds the contents of `other`. Its materials and prototypes replace the ones with the same name
+    fn merge(&mut self, other: Library) {
+        self.materials.extend(other.materials);
+        self.prototypes.extend(other.prototypes);
+        self.objects.extend(other.objects);
+        self.lights.extend(other.lights);
+    }
+
+    /// Loads the files included by `file`, and the files they include.
+    /// Every loaded path is added to `files` and every image read for them to `images`,
+    /// `chain` holds the files being included to catch cycles
+    fn load_includes(
+        file: &Path,
+        includes: &[PathBuf],
+        chain: &mut Vec<PathBuf>,
+        files: &mut Vec<PathBuf>,
+        images: &mut Vec<PathBuf>,
+    ) -> Result<Library, String> {
+        let mut library = Library::default();
+
+        for include in includes {
+            let path = file.parent().unwrap_or_else(|| Path::new("")).join(include);
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if chain.contains(&canonical) {
+                return Err(format!("{} includes itself", path.display()));
+            }
+
+            let mut included: Library = read_ron(&path)?;
+            files.push(path.clone());
+            included.load_images(&path, images)?;
+
+            chain.push(canonical);
+            let mut nested = Library::load_includes(&path, &included.include, chain, files, images)?;
+            chain.pop();
+
+            included.include.clear();
+            nested.merge(included);
+            library.merge(nested);
+        }
+
+        Ok(library)
+    }
+
+    /// Reads the images of the shapes and materials defined in `file`, adding their paths to `images`
+    fn load_images(&mut self, file: &Path, images: &mut Vec<PathBuf>) -> Result<(), String> {
+        let dir = file.parent().unwrap_or_else(|| Path::new(""));
+        for node in self.objects.iter_mut().chain(self.prototypes.values_mut()) {
+            node.visit_shapes_mut(&mut |shape| {
+                if let Shape::Heightfield { image, heights, .. } = shape {
+                    let path = dir.join(image.as_path());
+                    *heights = Some(Arc::new(read_heights(&path)?));
+                    images.push(path);
+                }
+                Ok(())
+            })?;
+        }
+
+        let mut load_mask = |mat: &mut Material| {
+            if let Some(mask) = &mut mat.mask {
+                let path = dir.join(mask.image.as_path());
+                mask.alpha = Some(Arc::new(read_alpha(&path)?));
+                images.push(path);
+            }
+            Ok(())
+        };
+        for node in self.objects.iter_mut().chain(self.prototypes.values_mut()) {
+            node.visit_materials_mut(&mut load_mask)?;
+        }
+        self.materials.values_mut().try_for_each(load_mask)
+    }
+}
+
+fn read_ron<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let src = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    ron::de::from_str(&src).map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+}
+
+/// Reads a PNG, with 8 bits per channel
+fn read_png(path: &Path) -> Result<(png::OutputInfo, Vec<u8>), String> {
+    let error = |e: &dyn std::fmt::Display| format!("Could not read {}: {}", path.display(), e);
+    let file = std::fs::File::open(path).map_err(|e| error(&e))?;
+    // The decoder turns every image into 8 bits per channel
+    let (info, mut reader) = png::Decoder::new(file).read_info().map_err(|e| error(&e))?;
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| error(&e))?;
+    Ok((info, data))
+}
+
+/// Reads a grayscale PNG as heights from 0 for black to 1 for white, one row of heights per row of pixels
+fn read_heights(path: &Path) -> Result<na::DMatrix<f32>, String> {
+    let (info, data) = read_png(path)?;
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        _ => return Err(format!("{} is not a grayscale image", path.display())),
+    };
+    if info.width < 2 || info.height < 2 {
+        return Err(format!(
+            "{} is too small for a heightfield, it needs at least 2x2 pixels",
+            path.display()
+        ));
+    }
+
+    Ok(na::DMatrix::from_fn(
+        info.height as usize,
+        info.width as usize,
+        |row, col| f32::from(data[row * info.line_size + col * channels]) / 255.0,
+    ))
+}
+
+/// Reads the alpha channel of a PNG from 0 to 1, or the brightness of the pixels if it has none
+fn read_alpha(path: &Path) -> Result<na::DMatrix<f32>, String> {
+    let (info, data) = read_png(path)?;
+    // Offset of the channel that is read, and the number of channels per pixel
+    let (channel, channels) = match info.color_type {
+        png::ColorType::Grayscale => (0, 1),
+        png::ColorType::GrayscaleAlpha => (1, 2),
+        png::ColorType::RGBA => (3, 4),
+        _ => return Err(format!("{} is neither grayscale nor has an alpha channel", path.display())),
+    };
+    Ok(na::DMatrix::from_fn(
+        info.height as usize,
+        info.width as usize,
+        |row, col| f32::from(data[row * info.line_size + col * channels + channel]) / 255.0,
+    ))
+}
+
+/// Reads a Radiance HDR image
+fn read_hdr(path: &Path) -> Result<EnvironmentMap, String> {
+    let error = |e: &dyn std::fmt::Display| format!("Could not read {}: {}", path.display(), e);
+    let data = std::fs::read(path).map_err(|e| error(&e))?;
+    EnvironmentMap::decode(&data).map_err(|e| error(&e))
+}
+
+/// Part of the image that should be traced
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Crop {
+    Pixels {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    /// Fractions of the image size
+    Normalized {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+}
+
+impl Crop {
+    /// The cropped region of an image of the given size. It is clamped to the image
+    /// and is at least one pixel large, unless the image is empty
+    pub fn region(&self, size: (u16, u16)) -> Tile {
+        let (x, y, width, height) = match *self {
+            Crop::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => (x as f32, y as f32, width as f32, height as f32),
+            Crop::Normalized {
+                x,
+                y,
+                width,
+                height,
+            } => (
+                x * size.0 as f32,
+                y * size.1 as f32,
+                width * size.0 as f32,
+                height * size.1 as f32,
+            ),
+        };
+
+        let x = (x.round().max(0.0) as u16).min(size.0.saturating_sub(1));
+        let y = (y.round().max(0.0) as u16).min(size.1.saturating_sub(1));
+        Tile {
+            x,
+            y,
+            width: (width.round().max(1.0) as u16).min(size.0 - x),
+            height: (height.round().max(1.0) as u16).min(size.1 - y),
+        }
+    }
+}
+
+/// Values that change over a sequence of frames
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Animation {
+    #[serde(default = "default_frames")]
+    frames: u32,
+    /// Blurs whatever the tracks move by setting its `motion` to where it is at the next frame.
+    /// Lights have no `motion`, so they stay where they are at the start of the frame
+    #[serde(default)]
+    motion_blur: bool,
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+impl Default for Animation {
+    fn default() -> Self {
+        Animation {
+            frames: default_frames(),
+            motion_blur: false,
+            tracks: Vec::new(),
+        }
+    }
+}
+
+/// Keys of a track are pairs of a frame number and the value at that frame, sorted by the frame
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum Track {
+    Camera {
+        #[serde(default)]
+        interpolation: Interpolation,
+        keys: Vec<(f32, Position)>,
+    },
+    /// Moves every object, group or instance with the name
+    Object {
+        name: String,
+        #[serde(default)]
+        interpolation: Interpolation,
+        keys: Vec<(f32, Position)>,
+    },
+    Light {
+        name: String,
+        #[serde(default)]
+        interpolation: Interpolation,
+        keys: Vec<(f32, Position)>,
+    },
+    /// Changes a number of one of the `materials` of the scene
+    Material {
+        name: String,
+        property: MaterialProperty,
+        #[serde(default)]
+        interpolation: Interpolation,
+        keys: Vec<(f32, f32)>,
+    },
+    /// Changes a color of one of the `materials` of the scene
+    MaterialColor {
+        name: String,
+        property: MaterialColor,
+        #[serde(default)]
+        interpolation: Interpolation,
+        keys: Vec<(f32, Color)>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+enum MaterialProperty {
+    PhongPart,
+    ReflectPart,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+enum MaterialColor {
+    Ambient,
+    Diffuse,
+    Specular,
+    Shininess,
+}
+
+impl Track {
+    fn name(&self) -> Option<&str> {
+        match self {
+            Track::Camera { .. } => None,
+            Track::Object { name, .. }
+            | Track::Light { name, .. }
+            | Track::Material { name, .. }
+            | Track::MaterialColor { name, .. } => Some(name),
+        }
+    }
+
+    fn frames(&self) -> Vec<f32> {
+        match self {
+            Track::Camera { keys, .. } | Track::Object { keys, .. } | Track::Light { keys, .. } => {
+                keys.iter().map(|key| key.0).collect()
+            }
+            Track::Material { keys, .. } => keys.iter().map(|key| key.0).collect(),
+            Track::MaterialColor { keys, .. } => keys.iter().map(|key| key.0).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Scene {
     #[serde(default = "default_size")]
     pub size: (u16, u16),
@@ -148,12 +1243,412 @@ pub struct Scene {
     #[serde(default)]
     pub multisample: bool,
     #[serde(default)]
+    pub tile_order: TileOrder,
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    #[serde(default)]
     camera: Position,
-    objects: Vec<Object>,
+    /// Where the camera is at the end of the frame, if it moves
+    #[serde(default)]
+    camera_motion: Option<Position>,
+    /// Part of the frame during which the shutter is open, from 0 to 1. Objects and the camera
+    /// move from their position at 0 to their `motion` at 1, and blur by the part they cover
+    #[serde(default = "default_shutter")]
+    pub shutter: (f32, f32),
+    /// Fills the space between the objects
+    #[serde(default)]
+    pub fog: Option<Fog>,
+    /// Traces light from the lights before the image, for the light that mirrors focus onto surfaces
+    #[serde(default)]
+    pub photon_mapping: Option<PhotonMapping>,
+    /// Darkens the ambient light in creases and corners
+    #[serde(default)]
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    /// Lights the scene with an image of its surroundings, which is also seen where rays leave the scene
+    #[serde(default)]
+    pub environment: Option<Environment>,
+    /// Tracks that turn the scene into a sequence of frames, see `Scene::frame`
+    #[serde(default)]
+    animation: Animation,
+    /// Files whose objects, lights, materials and prototypes are added to the scene.
+    /// Paths are relative to the scene file
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// Materials that objects can refer to by name
+    #[serde(default)]
+    materials: HashMap<String, Material>,
+    /// Nodes that are only shown where instances refer to them
+    #[serde(default)]
+    prototypes: HashMap<String, Node>,
+    #[serde(default)]
+    objects: Vec<Node>,
+    #[serde(default)]
     lights: Vec<LightSource>,
+    /// Every file read because of `include`
+    #[serde(skip)]
+    included: Vec<PathBuf>,
+    /// Every image read for the shapes of the scene and the included files
+    #[serde(skip)]
+    images: Vec<PathBuf>,
+    /// Photons traced by the first render of the scene or of a copy with another camera
+    #[serde(skip)]
+    pub photons: raytrace::PhotonCache,
 }
 
 impl Scene {
+    /// Reads a scene file together with the files it includes. Objects and lights of included files come
+    /// before the ones of the scene, and materials and prototypes defined in the scene replace included ones
+    pub fn load(path: &Path) -> Result<Scene, String> {
+        let mut scene: Scene = read_ron(path)?;
+
+        let mut chain = vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())];
+        let mut library = Library::load_includes(
+            path,
+            &scene.include,
+            &mut chain,
+            &mut scene.included,
+            &mut scene.images,
+        )?;
+        let mut own = Library {
+            include: Vec::new(),
+            materials: std::mem::take(&mut scene.materials),
+            prototypes: std::mem::take(&mut scene.prototypes),
+            objects: std::mem::take(&mut scene.objects),
+            lights: std::mem::take(&mut scene.lights),
+        };
+        own.load_images(path, &mut scene.images)?;
+        library.merge(own);
+        if let Some(environment) = &mut scene.environment {
+            let image = path.parent().unwrap_or_else(|| Path::new("")).join(&environment.image);
+            environment.map = Some(Arc::new(read_hdr(&image)?));
+            scene.images.push(image);
+        }
+        scene.materials = library.materials;
+        scene.prototypes = library.prototypes;
+        scene.objects = library.objects;
+        scene.lights = library.lights;
+
+        scene.check()?;
+        Ok(scene)
+    }
+
+    /// Makes sure that every material, prototype and object name refers to something, and that no prototype
+    /// contains itself. Scenes loaded with `load` are already checked
+    pub fn check(&self) -> Result<(), String> {
+        let unknown = |kind: &str, name: &str| {
+            if self.include.len() > self.included.len() {
+                format!(
+                    "Unknown {} `{}`. Included files are only read by `Scene::load`",
+                    kind, name
+                )
+            } else {
+                format!("Unknown {} `{}`", kind, name)
+            }
+        };
+
+        if self.size.0 == 0 || self.size.1 == 0 {
+            return Err(format!(
+                "Image size must be at least 1x1, but it is {}x{}",
+                self.size.0, self.size.1
+            ));
+        }
+
+        let mut nodes = Vec::new();
+        for node in self.objects.iter().chain(self.prototypes.values()) {
+            node.visit(&mut |node| nodes.push(node));
+        }
+        self.check_tracks(&nodes)?;
+        if let Some(fog) = &self.fog {
+            fog.check()?;
+        }
+        if let Some(photon_mapping) = &self.photon_mapping {
+            photon_mapping.check()?;
+        }
+        self.materials.values().try_for_each(Material::check)?;
+
+        let linked = self.lights.iter().filter_map(|light| light.objects.as_ref());
+        if linked.clone().count() > raytrace::MAX_LINKED_LIGHTS {
+            return Err(format!(
+                "At most {} lights can shine on only some of the objects",
+                raytrace::MAX_LINKED_LIGHTS
+            ));
+        }
+        for name in linked.flatten() {
+            if !nodes.iter().any(|node| node.name() == Some(name)) {
+                return Err(unknown("object", name));
+            }
+        }
+
+        for node in nodes {
+            let (mat, of) = match node {
+                Node::Object(object) => {
+                    object.shape.check()?;
+                    (Some(&object.mat), None)
+                }
+                Node::Group(_) => (None, None),
+                Node::Instance(instance) => (instance.mat.as_ref(), Some(&instance.of)),
+            };
+
+            match mat {
+                Some(MaterialRef::Named(name)) if !self.materials.contains_key(name) => {
+                    return Err(unknown("material", name));
+                }
+                Some(MaterialRef::Inline(material)) => material.check()?,
+                _ => (),
+            }
+            if let Some(of) = of {
+                if !self.prototypes.contains_key(of) {
+                    return Err(unknown("prototype", of));
+                }
+            }
+        }
+
+        let mut checked = HashSet::new();
+        for name in self.prototypes.keys() {
+            self.check_prototype_cycle(name, &mut Vec::new(), &mut checked)?;
+        }
+
+        match &self.environment {
+            Some(environment) if environment.map.is_none() => Err(format!(
+                "Environment image {} is only read by `Scene::load`",
+                environment.image.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes sure that the tracks refer to things in the scene and that their keys are sorted
+    fn check_tracks(&self, nodes: &[&Node]) -> Result<(), String> {
+        for track in &self.animation.tracks {
+            let frames = track.frames();
+            if frames.is_empty() {
+                return Err("Animation track without keys".to_string());
+            }
+            if frames.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(format!(
+                    "Keys of an animation track are not sorted by their frames: {:?}",
+                    frames
+                ));
+            }
+
+            let (kind, known) = match track {
+                Track::Camera { .. } => continue,
+                Track::Object { name, .. } => (
+                    "object",
+                    nodes.iter().any(|node| node.name() == Some(name)),
+                ),
+                Track::Light { name, .. } => (
+                    "light",
+                    self.lights
+                        .iter()
+                        .any(|light| light.name.as_ref() == Some(name)),
+                ),
+                Track::Material { name, .. } | Track::MaterialColor { name, .. } => {
+                    ("material", self.materials.contains_key(name))
+                }
+            };
+            if !known {
+                return Err(format!(
+                    "Animation track refers to unknown {} `{}`",
+                    kind,
+                    track.name().unwrap_or_default()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// `chain` holds the prototypes whose instances led to this one, and `checked` the prototypes
+    /// that are known not to lead back to themselves, which are only checked once
+    fn check_prototype_cycle<'a>(
+        &'a self,
+        name: &'a str,
+        chain: &mut Vec<&'a str>,
+        checked: &mut HashSet<&'a str>,
+    ) -> Result<(), String> {
+        if chain.contains(&name) {
+            return Err(format!("Prototype `{}` contains an instance of itself", name));
+        }
+        if checked.contains(name) {
+            return Ok(());
+        }
+
+        let mut instanced = Vec::new();
+        if let Some(node) = self.prototypes.get(name) {
+            node.visit(&mut |node| {
+                if let Node::Instance(instance) = node {
+                    instanced.push(instance.of.as_str());
+                }
+            });
+        }
+
+        chain.push(name);
+        for of in instanced {
+            self.check_prototype_cycle(of, chain, checked)?;
+        }
+        chain.pop();
+        checked.insert(name);
+        Ok(())
+    }
+
+    /// Part of the image that gets traced: either the crop window or the whole image
+    pub fn region(&self) -> Tile {
+        match self.crop {
+            Some(crop) => crop.region(self.size),
+            None => Tile {
+                x: 0,
+                y: 0,
+                width: self.size.0,
+                height: self.size.1,
+            },
+        }
+    }
+
+    pub fn camera(&self) -> na::Isometry3<f32> {
+        self.camera.clone().into_raytrace()
+    }
+
+    pub fn camera_motion(&self) -> Option<na::Isometry3<f32>> {
+        self.camera_motion.clone().map(Position::into_raytrace)
+    }
+
+    /// Also stops the camera from moving
+    pub fn set_camera(&mut self, camera: na::Isometry3<f32>) {
+        self.camera = Position::from_raytrace(camera);
+        self.camera_motion = None;
+    }
+
+    /// Number of frames of the animation. At least 1, which is a still image
+    pub fn frames(&self) -> u32 {
+        self.animation.frames.max(1)
+    }
+
+    /// The scene at frame `n` of its animation. The scene itself stays as it was, so it can be
+    /// loaded once for all the frames
+    pub fn frame(&self, n: u32) -> Scene {
+        let mut scene = self.clone();
+        // Objects and lights move between frames, so every frame traces its own photons
+        scene.photons = raytrace::PhotonCache::default();
+        let now = n as f32;
+        let next = if self.animation.motion_blur { Some(now + 1.0) } else { None };
+
+        for track in &self.animation.tracks {
+            match track {
+                Track::Camera {
+                    interpolation,
+                    keys,
+                } => {
+                    if let Some(pos) = animation::sample(keys, *interpolation, now) {
+                        scene.camera = pos;
+                        let end = next.and_then(|next| animation::sample(keys, *interpolation, next));
+                        if end.is_some() {
+                            scene.camera_motion = end;
+                        }
+                    }
+                }
+                Track::Object {
+                    name,
+                    interpolation,
+                    keys,
+                } => {
+                    let (now, next) = (
+                        animation::sample(keys, *interpolation, now),
+                        next.and_then(|next| animation::sample(keys, *interpolation, next)),
+                    );
+                    let now = match now {
+                        Some(now) => now,
+                        None => continue,
+                    };
+                    let visit = &mut |node: &mut Node| {
+                        if node.name() == Some(name) {
+                            let (pos, motion) = node.placement_mut();
+                            *pos = now.clone();
+                            // Without motion blur, motion that was set by hand is kept
+                            if let Some(next) = &next {
+                                *motion = Some(next.clone());
+                            }
+                        }
+                    };
+                    for node in scene.objects.iter_mut().chain(scene.prototypes.values_mut()) {
+                        node.visit_mut(visit);
+                    }
+                }
+                Track::Light {
+                    name,
+                    interpolation,
+                    keys,
+                } => {
+                    if let Some(pos) = animation::sample(keys, *interpolation, now) {
+                        for light in &mut scene.lights {
+                            if light.name.as_ref() == Some(name) {
+                                light.pos = pos.clone();
+                            }
+                        }
+                    }
+                }
+                Track::Material {
+                    name,
+                    property,
+                    interpolation,
+                    keys,
+                } => {
+                    let value = animation::sample(keys, *interpolation, now);
+                    if let (Some(value), Some(mat)) = (value, scene.materials.get_mut(name)) {
+                        *match property {
+                            MaterialProperty::PhongPart => &mut mat.phong.part,
+                            MaterialProperty::ReflectPart => &mut mat.reflect.part,
+                        } = value;
+                    }
+                }
+                Track::MaterialColor {
+                    name,
+                    property,
+                    interpolation,
+                    keys,
+                } => {
+                    let value = animation::sample(keys, *interpolation, now);
+                    if let (Some(value), Some(mat)) = (value, scene.materials.get_mut(name)) {
+                        *match property {
+                            MaterialColor::Ambient => &mut mat.phong.ambient,
+                            MaterialColor::Diffuse => &mut mat.phong.diffuse,
+                            MaterialColor::Specular => &mut mat.phong.specular,
+                            MaterialColor::Shininess => &mut mat.phong.shininess,
+                        } = value;
+                    }
+                }
+            }
+        }
+
+        scene
+    }
+
+    /// Shrinks the image, and the crop window with it, by an integer factor
+    pub fn downscale(&mut self, factor: u16) {
+        let factor = factor.max(1);
+        let shrink = |value: u16| (value / factor).max(1);
+
+        self.size = (shrink(self.size.0), shrink(self.size.1));
+        if let Some(Crop::Pixels {
+            x,
+            y,
+            width,
+            height,
+        }) = self.crop
+        {
+            self.crop = Some(Crop::Pixels {
+                x: x / factor,
+                y: y / factor,
+                width: shrink(width),
+                height: shrink(height),
+            });
+        }
+    }
+
+    /// Files besides the scene file itself that are read when the scene is loaded
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        self.included.iter().chain(&self.images).cloned().collect()
+    }
+
     pub fn unpack(
         self,
     ) -> (
@@ -161,20 +1656,115 @@ impl Scene {
         Vec<raytrace::RaytraceObject>,
         Vec<raytrace::LightSource>,
     ) {
+        let mut placed = Vec::new();
+        let mut flattener = Flattener::new(&self.materials, &self.prototypes);
+        for node in &self.objects {
+            flattener.flatten(node, Placement::fixed(Transform::identity()), &[], &mut placed);
+        }
+
+        let (names, objects): (Vec<_>, Vec<_>) = placed
+            .into_iter()
+            .filter_map(|placed| {
+                let names = placed.names.clone();
+                placed.into_raytrace().map(|object| (names, object))
+            })
+            .unzip();
         (
             self.camera.into_raytrace(),
-            self.objects
-                .into_iter()
-                .map(Object::into_raytrace)
-                .collect(),
+            objects,
             self.lights
                 .into_iter()
-                .map(LightSource::into_raytrace)
+                .map(|light| light.into_raytrace(&names))
                 .collect(),
         )
     }
 }
 
+/// Replaces the camera in the source of a scene, leaving the rest of the text as it was
+pub fn replace_camera(src: &str, camera: na::Isometry3<f32>) -> Result<String, String> {
+    let value = ron::ser::to_string(&Position::from_raytrace(camera)).map_err(|e| format!("{}", e))?;
+
+    let mut result = String::with_capacity(src.len() + value.len());
+    match top_level_field(src, "camera") {
+        Some(range) => {
+            result.push_str(&src[..range.start]);
+            result.push_str(&value);
+            result.push_str(&src[range.end..]);
+        }
+        None => {
+            let start = src
+                .find('(')
+                .ok_or_else(|| "The scene is not a struct".to_string())?;
+            result.push_str(&src[..=start]);
+            result.push_str("\n    camera: ");
+            result.push_str(&value);
+            result.push(',');
+            result.push_str(&src[start + 1..]);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Finds the value of a field of the outermost struct in RON source
+fn top_level_field(src: &str, name: &str) -> Option<std::ops::Range<usize>> {
+    let bytes = src.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    let mut value_start = None;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i + 1 < bytes.len() && &bytes[i..i + 2] != b"*/" {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => {
+                depth -= 1;
+                if depth == 1 {
+                    if let Some(start) = value_start {
+                        return Some(start..i + 1);
+                    }
+                }
+            }
+            c if depth == 1 && value_start.is_none() && (c.is_ascii_alphabetic() || c == b'_') => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let ident = &src[start..i];
+
+                let after = src[i..].trim_start();
+                if ident == name && after.starts_with(':') {
+                    let value = after[1..].trim_start();
+                    value_start = Some(src.len() - value.len());
+                    i = value_start.unwrap();
+                }
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    None
+}
+
 fn default_size() -> (u16, u16) {
     (640, 480)
 }
@@ -186,3 +1776,518 @@ fn default_fov() -> f32 {
 fn default_steps() -> usize {
     4
 }
+
+fn default_shutter() -> (f32, f32) {
+    (0.0, 1.0)
+}
+
+fn default_frames() -> u32 {
+    1
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_scatter_scale() -> (f32, f32) {
+    (1.0, 1.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{replace_camera, Crop, MaterialRef, Node, Position, Scale, Scene, Transform};
+    use crate::{raytrace::MAX_LINKED_LIGHTS, tiles::Tile};
+
+    #[test]
+    fn replaces_camera() {
+        let src = "(\n    size: (10, 10), // camera: (x)\n    camera: (\n        trans: ( z: -2.0 ),\n    ),\n    objects: [],\n)";
+        let camera = na::Isometry3::new(na::Vector3::new(1.0, 2.0, 3.0), na::Vector3::y() * 0.5);
+
+        let result = replace_camera(src, camera).unwrap();
+        assert!(result.starts_with("(\n    size: (10, 10), // camera: (x)\n    camera: (trans:"));
+        assert!(result.ends_with("),\n    objects: [],\n)"));
+
+        let start = result.find("\n    camera: ").unwrap() + "\n    camera: ".len();
+        let end = result.rfind(",\n    objects").unwrap();
+        let parsed = ron::de::from_str::<Position>(&result[start..end]).unwrap().into_raytrace();
+        assert!((parsed.translation.vector - camera.translation.vector).norm() < 1e-4);
+        assert!(parsed.rotation.angle_to(&camera.rotation) < 1e-4);
+    }
+
+    #[test]
+    fn inserts_camera() {
+        let result = replace_camera("(objects: [])", na::Isometry3::identity()).unwrap();
+        assert!(result.starts_with("(\n    camera: (trans:"));
+        assert!(result.ends_with(",objects: [])"));
+    }
+
+    #[test]
+    fn includes_and_named_materials() {
+        let dir = std::env::temp_dir().join(format!("raytrace-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(
+            dir.join("lib/common.ron"),
+            r#"(
+                materials: { "red": (phong: (part: 0.1)), "blue": (phong: (part: 0.2)) },
+                objects: [ (pos: (), shape: Ball(1.0), mat: "blue") ],
+            )"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("scene.ron"),
+            r#"(
+                include: ["lib/common.ron"],
+                materials: { "red": (phong: (part: 0.3)) },
+                objects: [ (pos: (), shape: Ball(2.0), mat: "red") ],
+                lights: [],
+            )"#,
+        )
+        .unwrap();
+
+        let scene = Scene::load(&dir.join("scene.ron")).unwrap();
+        assert_eq!(scene.dependencies(), vec![dir.join("lib/common.ron")]);
+        assert_eq!(scene.objects.len(), 2);
+        assert!(matches!(&scene.objects[0], Node::Object(object)
+            if matches!(&object.mat, MaterialRef::Named(name) if name == "blue")));
+
+        let (_, objects, _) = scene.unpack();
+        assert_eq!(objects[0].mat.phong.part, 0.2);
+        // The scene's own definition replaces the included one
+        assert_eq!(objects[1].mat.phong.part, 0.3);
+
+        std::fs::write(dir.join("lib/common.ron"), r#"(include: ["../scene.ron"])"#).unwrap();
+        assert!(Scene::load(&dir.join("scene.ron")).unwrap_err().contains("includes itself"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn groups_compose_positions() {
+        let scene: Scene = ron::de::from_str(
+            r#"(
+                objects: [
+                    Group(
+                        pos: (trans: (x: 1.0), rot: Euler(yaw: 90.0)),
+                        children: [
+                            (pos: (trans: (z: 2.0)), shape: Ball(1.0)),
+                            (pos: (), children: [(pos: (trans: (y: 3.0)), shape: Ball(1.0))]),
+                        ],
+                    ),
+                    (pos: (trans: (x: 5.0)), shape: Ball(1.0)),
+                ],
+            )"#,
+        )
+        .unwrap();
+
+        let (_, objects, _) = scene.unpack();
+        let positions = objects
+            .iter()
+            .map(|object| object.pos.translation.vector)
+            .collect::<Vec<_>>();
+        assert_eq!(positions.len(), 3);
+        // Yawing by 90 degrees turns +z into +x
+        assert!((positions[0] - na::Vector3::new(3.0, 0.0, 0.0)).norm() < 1e-5);
+        assert!((positions[1] - na::Vector3::new(1.0, 3.0, 0.0)).norm() < 1e-5);
+        assert!((positions[2] - na::Vector3::new(5.0, 0.0, 0.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn scaled_transforms_compose() {
+        let position = |x: f32, yaw: f32| {
+            ron::de::from_str::<Position>(&format!("(trans: (x: {}), rot: Euler(yaw: {}))", x, yaw)).unwrap()
+        };
+        let parent = Transform::new(&position(1.0, 0.0), &Scale::Axes { x: 2.0, y: 1.0, z: 1.0 });
+        let child = Transform::new(&position(1.0, 90.0), &Scale::Uniform(3.0));
+        let composed = parent.then(child);
+
+        // The same point placed by applying both transforms one after the other
+        let point = na::Point3::new(0.0, 0.0, 1.0);
+        let apply = |t: &Transform, p: na::Point3<f32>| t.iso * na::Point3::from(t.linear * p.coords);
+        let expected = apply(&parent, apply(&child, point));
+        assert!((apply(&composed, point) - expected).norm() < 1e-5);
+        assert!((expected - na::Point3::new(9.0, 0.0, 0.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn instances_share_shapes() {
+        let scene: Scene = ron::de::from_str(
+            r#"(
+                materials: { "bark": (phong: (part: 0.5)) },
+                prototypes: {
+                    "tree": Group(children: [
+                        (pos: (), shape: Cuboid(x: 0.1, y: 1.0, z: 0.1), mat: "bark"),
+                        (pos: (trans: (y: 1.5)), shape: Ball(0.5)),
+                    ]),
+                    "row": Instance(of: "tree", layout: Array(count: 3, step: (trans: (x: 2.0)))),
+                },
+                objects: [
+                    Instance(of: "row", layout: Grid(counts: (1, 1, 2), spacing: (z: 5.0))),
+                    Instance(of: "tree", mat: (reflect: (part: 0.0)), layout: Scatter(count: 10, size: (x: 4.0, z: 4.0))),
+                ],
+            )"#,
+        )
+        .unwrap();
+        scene.check().unwrap();
+
+        let (_, objects, _) = scene.unpack();
+        assert_eq!(objects.len(), 2 * 3 * 2 + 2 * 10);
+        assert!(objects.iter().all(|object| object.shape.as_shape::<nc::shape::Cuboid<f32>>().is_some()
+            || object.shape.as_shape::<nc::shape::Ball<f32>>().is_some()));
+
+        let trunk = objects[4].pos.translation.vector;
+        assert!((trunk - na::Vector3::new(4.0, 0.0, 0.0)).norm() < 1e-5);
+        assert_eq!(objects[4].mat.phong.part, 0.5);
+        // The scattered trees replace the bark with the default material
+        assert_eq!(objects[12].mat.phong.part, 1.0);
+
+        // Both trunks point to the same shape
+        let first = &*objects[0].shape as *const dyn nc::shape::Shape<f32> as *const u8;
+        let second = &*objects[2].shape as *const dyn nc::shape::Shape<f32> as *const u8;
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn lights_shine_on_the_linked_objects() {
+        let src = |linked: &str| {
+            format!(
+                r#"(
+                    prototypes: {{
+                        "lamp post": Group(children: [
+                            (name: "pole", pos: (), shape: Ball(0.1)),
+                            (name: "glass", pos: (), shape: Ball(0.2), casts_shadows: false),
+                        ]),
+                    }},
+                    objects: [
+                        (pos: (), shape: Ball(1.0), visible_to_camera: false),
+                        Instance(name: "street", of: "lamp post", layout: Array(count: 2, step: (trans: (x: 2.0)))),
+                    ],
+                    lights: [
+                        (pos: (), brightness: (r: 1.0, g: 1.0, b: 1.0), kind: Point, objects: Some([{}])),
+                        (pos: (), brightness: (r: 1.0, g: 1.0, b: 1.0), kind: Point),
+                    ],
+                )"#,
+                linked
+            )
+        };
+        let scene: Scene = ron::de::from_str(&src(r#""glass""#)).unwrap();
+        scene.check().unwrap();
+
+        let (_, objects, lights) = scene.unpack();
+        assert!(!objects[0].visibility.camera && objects[0].visibility.shadows);
+        assert!(!objects[2].visibility.shadows && objects[2].visibility.camera);
+        assert_eq!(lights[0].objects, Some(vec![2, 4]));
+        assert_eq!(lights[1].objects, None);
+
+        // Groups and instances light all of their objects
+        let scene: Scene = ron::de::from_str(&src(r#""street""#)).unwrap();
+        let (_, _, lights) = scene.unpack();
+        assert_eq!(lights[0].objects, Some(vec![1, 2, 3, 4]));
+
+        let scene: Scene = ron::de::from_str(&src(r#""lamp""#)).unwrap();
+        assert_eq!(scene.check().unwrap_err(), "Unknown object `lamp`");
+        let instance = r#"(objects: [ Instance(of: "lamp post", casts_shadows: false) ])"#;
+        assert!(ron::de::from_str::<Scene>(instance).is_err());
+
+        let light = r#"(pos: (), brightness: (r: 1.0, g: 1.0, b: 1.0), kind: Point, objects: Some([]))"#;
+        let lights = vec![light; MAX_LINKED_LIGHTS + 1].join(", ");
+        let scene: Scene = ron::de::from_str(&format!("(lights: [{}])", lights)).unwrap();
+        assert_eq!(
+            scene.check().unwrap_err(),
+            format!("At most {} lights can shine on only some of the objects", MAX_LINKED_LIGHTS)
+        );
+    }
+
+    #[test]
+    fn prototype_cycles_are_errors() {
+        let scene: Scene = ron::de::from_str(
+            r#"(prototypes: { "a": Instance(of: "b"), "b": Group(children: [Instance(of: "a")]) })"#,
+        )
+        .unwrap();
+        assert!(scene.check().unwrap_err().contains("contains an instance of itself"));
+
+        // Every prototype instances the next one twice, which is a lot of paths but no cycle
+        let prototypes = (0..64)
+            .map(|i| {
+                let next = format!(r#"Instance(of: "p{}")"#, i + 1);
+                format!(r#""p{}": Group(children: [{1}, {1}])"#, i, next)
+            })
+            .collect::<Vec<_>>();
+        let src = format!(r#"(prototypes: {{ {}, "p64": Group(children: []) }})"#, prototypes.join(", "));
+        let scene: Scene = ron::de::from_str(&src).unwrap();
+        assert_eq!(scene.check(), Ok(()));
+    }
+
+    #[test]
+    fn csg_shapes() {
+        let scene: Scene = ron::de::from_str(
+            r#"(
+                objects: [(
+                    pos: (),
+                    shape: Difference([
+                        (shape: Cuboid(x: 1.0, y: 1.0, z: 1.0)),
+                        (pos: (trans: (z: -1.0)), scale: Uniform(0.5), shape: Union([
+                            (shape: Ball(1.0)),
+                            (pos: (trans: (x: 1.0)), shape: Ball(1.0)),
+                        ])),
+                    ]),
+                )],
+            )"#,
+        )
+        .unwrap();
+
+        let (_, objects, _) = scene.unpack();
+        assert_eq!(objects.len(), 1);
+        assert!(objects[0].shape.as_shape::<crate::shapes::Csg>().is_some());
+    }
+
+    #[test]
+    fn blobs_need_a_radius_per_center() {
+        let scene: Scene = ron::de::from_str(
+            r#"(objects: [(
+                pos: (),
+                shape: Blobs(centers: [(x: -1.0), (x: 1.0)], radii: [1.0], threshold: 0.5),
+                mat: (reflect: (part: 0.0)),
+            )])"#,
+        )
+        .unwrap();
+        assert_eq!(scene.check().unwrap_err(), "Blobs have 2 centers but 1 radii");
+
+        let scene: Scene = ron::de::from_str(
+            "(objects: [(pos: (), shape: Blobs(centers: [(x: -1.0), (x: 1.0)], radii: [1.0, 0.0], threshold: 0.5))])",
+        )
+        .unwrap();
+        assert_eq!(scene.check().unwrap_err(), "Blob radii must be positive, but one of them is 0");
+
+        for threshold in &["0.0", "1.0", "-0.5"] {
+            let src = format!(
+                "(objects: [(pos: (), shape: Blobs(centers: [(x: 0.0)], radii: [1.0], threshold: {}))])",
+                threshold
+            );
+            let scene: Scene = ron::de::from_str(&src).unwrap();
+            assert!(scene.check().unwrap_err().contains("between 0 and 1"), "{}", threshold);
+        }
+    }
+
+    #[test]
+    fn crop_regions_stay_inside_the_image() {
+        let tile = |x, y, width, height| Tile {
+            x,
+            y,
+            width,
+            height,
+        };
+        let pixels = |x, y, width, height| Crop::Pixels {
+            x,
+            y,
+            width,
+            height,
+        };
+        let normalized = |x, y, width, height| Crop::Normalized {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        assert_eq!(pixels(10, 20, 30, 40).region((100, 100)), tile(10, 20, 30, 40));
+        assert_eq!(normalized(0.25, 0.5, 0.5, 0.25).region((200, 100)), tile(50, 50, 100, 25));
+        // Past the right and bottom edges the region shrinks, past the left and top edges it moves
+        assert_eq!(pixels(90, 95, 30, 30).region((100, 100)), tile(90, 95, 10, 5));
+        assert_eq!(pixels(150, 150, 10, 10).region((100, 100)), tile(99, 99, 1, 1));
+        assert_eq!(normalized(-0.5, 0.0, 0.2, 0.0).region((100, 100)), tile(0, 0, 20, 1));
+        assert_eq!(pixels(5, 5, 10, 10).region((0, 0)), tile(0, 0, 0, 0));
+
+        let empty: Scene = ron::de::from_str("(size: (0, 10))").unwrap();
+        assert_eq!(empty.check().unwrap_err(), "Image size must be at least 1x1, but it is 0x10");
+    }
+
+    #[test]
+    fn media_cannot_add_light() {
+        let fog: Scene = ron::de::from_str("(fog: Some((density: -0.1)))").unwrap();
+        assert!(fog.check().unwrap_err().contains("can't be negative"));
+
+        let volume: Scene = ron::de::from_str(
+            r#"(materials: {
+                "smoke": (volume: Some((scattering: (r: 0.5, g: 0.5, b: -0.5)))),
+            })"#,
+        )
+        .unwrap();
+        assert!(volume.check().unwrap_err().contains("can't be negative"));
+    }
+
+    #[test]
+    fn photons_are_gathered_from_a_positive_radius() {
+        for radius in &["0.0", "-0.1"] {
+            let src = format!("(photon_mapping: Some((count: 100, radius: {})))", radius);
+            let scene: Scene = ron::de::from_str(&src).unwrap();
+            assert!(scene.check().unwrap_err().contains("must be positive"), "{}", radius);
+        }
+    }
+
+    #[test]
+    fn heightfield_images_are_relative_to_their_file() {
+        let dir = std::env::temp_dir().join(format!("raytrace-heightfield-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+
+        use nc::shape::Shape;
+        use png::HasParameters;
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 3, 2);
+        encoder.set(png::ColorType::Grayscale);
+        encoder.set(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[0, 128, 255, 0, 128, 255])
+            .unwrap();
+        std::fs::write(dir.join("lib/terrain.png"), png).unwrap();
+
+        let terrain = r#"(objects: [(
+            pos: (),
+            shape: Heightfield(image: "terrain.png", scale: (x: 10.0, y: 2.0, z: 10.0)),
+        )])"#;
+        std::fs::write(dir.join("lib/terrain.ron"), terrain).unwrap();
+        std::fs::write(dir.join("scene.ron"), r#"(include: ["lib/terrain.ron"])"#).unwrap();
+
+        let scene = Scene::load(&dir.join("scene.ron")).unwrap();
+        assert_eq!(
+            scene.dependencies(),
+            vec![dir.join("lib/terrain.ron"), dir.join("lib/terrain.png")]
+        );
+        let (_, objects, _) = scene.unpack();
+        let shape = objects[0].shape.as_shape::<crate::shapes::Heightfield>();
+        assert_eq!(shape.unwrap().aabb(&na::Isometry3::identity()).maxs().y, 2.0);
+
+        // Without `Scene::load` the image is never read
+        let scene: Scene = ron::de::from_str(terrain).unwrap();
+        assert!(scene.check().unwrap_err().contains("only read by `Scene::load`"));
+
+        let scene: Scene = ron::de::from_str(
+            r#"(objects: [(
+                pos: (),
+                shape: Difference([
+                    (shape: Cuboid(x: 5.0, y: 1.0, z: 5.0)),
+                    (shape: Heightfield(image: "terrain.png", scale: (x: 10.0, y: 2.0, z: 10.0))),
+                ]),
+            )])"#,
+        )
+        .unwrap();
+        assert!(scene.check().unwrap_err().contains("no inside"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn environment_images_are_read_with_the_scene() {
+        let dir = std::env::temp_dir().join(format!("raytrace-environment-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Two pixels of 1.0 in every channel
+        let mut hdr = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        hdr.extend_from_slice(&[128, 128, 128, 129, 128, 128, 128, 129]);
+        std::fs::write(dir.join("sky.hdr"), hdr).unwrap();
+        let src = r#"(environment: Some((image: "sky.hdr", intensity: 2.0)))"#;
+        std::fs::write(dir.join("scene.ron"), src).unwrap();
+
+        let scene = Scene::load(&dir.join("scene.ron")).unwrap();
+        assert_eq!(scene.dependencies(), vec![dir.join("sky.hdr")]);
+        let environment = scene.environment.unwrap();
+        assert_eq!(environment.radiance(&na::Vector3::y()), crate::fb::Color::white() * 2.0);
+
+        let scene: Scene = ron::de::from_str(src).unwrap();
+        assert!(scene.check().unwrap_err().contains("only read by `Scene::load`"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn moving_groups_move_their_children() {
+        let scene: Scene = ron::de::from_str(
+            r#"(objects: [
+                Group(pos: (), motion: (trans: (x: 2.0)), children: [
+                    (pos: (trans: (y: 1.0)), shape: Ball(1.0)),
+                ]),
+                (pos: (), shape: Ball(1.0)),
+            ])"#,
+        )
+        .unwrap();
+
+        let (_, objects, _) = scene.unpack();
+        let end = objects[0].motion.expect("The child doesn't move with its group");
+        assert!((end.translation.vector - na::Vector3::new(2.0, 1.0, 0.0)).norm() < 1e-6);
+        assert!(objects[1].motion.is_none());
+    }
+
+    #[test]
+    fn frames_apply_the_tracks() {
+        let scene: Scene = ron::de::from_str(
+            r#"(
+                materials: {"paint": ()},
+                objects: [
+                    Group(name: "arm", pos: (), children: [
+                        (name: "ball", pos: (), shape: Ball(1.0), mat: "paint"),
+                    ]),
+                ],
+                animation: (
+                    frames: 11,
+                    motion_blur: true,
+                    tracks: [
+                        Object(name: "ball", keys: [(0, (trans: (x: 0.0))), (10, (trans: (x: 10.0)))]),
+                        Material(name: "paint", property: ReflectPart, interpolation: Step, keys: [(0, 0.0), (5, 0.5)]),
+                    ],
+                ),
+            )"#,
+        )
+        .unwrap();
+        scene.check().unwrap();
+        assert_eq!(scene.frames(), 11);
+
+        let frame = scene.frame(4);
+        assert_eq!(frame.materials["paint"].reflect.part, 0.0);
+        assert_eq!(scene.frame(5).materials["paint"].reflect.part, 0.5);
+
+        let (_, objects, _) = frame.unpack();
+        assert!((objects[0].pos.translation.vector.x - 4.0).abs() < 1e-6);
+        let end = objects[0].motion.expect("Motion blur doesn't move the object");
+        assert!((end.translation.vector.x - 5.0).abs() < 1e-6);
+
+        let mut unknown = scene.clone();
+        unknown.animation.tracks.push(super::Track::Light {
+            name: "sun".to_string(),
+            interpolation: Default::default(),
+            keys: vec![(0.0, Position::default())],
+        });
+        assert!(unknown.check().unwrap_err().contains("unknown light `sun`"));
+    }
+
+    #[test]
+    fn tracks_keep_motion_set_by_hand() {
+        let src = |motion_blur: bool| {
+            format!(
+                r#"(
+                    objects: [(name: "ball", pos: (), motion: (trans: (y: 1.0)), shape: Ball(1.0))],
+                    animation: (
+                        frames: 11,
+                        motion_blur: {},
+                        tracks: [
+                            Object(name: "ball", keys: [(0, (trans: (x: 0.0))), (10, (trans: (x: 10.0)))]),
+                        ],
+                    ),
+                )"#,
+                motion_blur
+            )
+        };
+        let end = |motion_blur: bool| {
+            let scene: Scene = ron::de::from_str(&src(motion_blur)).unwrap();
+            scene.check().unwrap();
+            let (_, objects, _) = scene.frame(4).unpack();
+            objects[0].motion.expect("The ball doesn't move").translation.vector
+        };
+
+        assert_eq!((end(false).x, end(false).y), (0.0, 1.0));
+        // Motion blur moves the ball along the track instead
+        assert!((end(true).x - 5.0).abs() < 1e-6);
+        assert_eq!(end(true).y, 0.0);
+    }
+}
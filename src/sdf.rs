@@ -0,0 +1,381 @@
+//! Implicit surfaces given by signed distance functions, traced by sphere tracing.
+//! Distances are negative inside the surface. Lengths are in scene units and angles in degrees
+
+use na::{Isometry3, Point3, Unit, Vector3};
+use nc::{
+    bounding_volume::AABB,
+    query::{Ray, RayCast, RayIntersection},
+    shape::{FeatureId, Shape},
+};
+use serde::{Deserialize, Serialize};
+
+/// Sphere tracing gives up after this many steps
+const MAX_STEPS: usize = 512;
+/// Points closer to the surface than this count as hits
+const HIT_DISTANCE: f32 = 1e-4;
+/// Offset used to find the gradient of the distance, which is the normal
+const GRADIENT_STEP: f32 = 1e-4;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Sdf {
+    Sphere(f32),
+    /// Given by half of its size along every axis
+    Box {
+        x: f32,
+        y: f32,
+        z: f32,
+    },
+    /// Lies in the xz plane
+    Torus {
+        radius: f32,
+        thickness: f32,
+    },
+    /// Along the y axis, from `-height` to `height`
+    Cylinder {
+        radius: f32,
+        height: f32,
+    },
+    Translate {
+        #[serde(default)]
+        x: f32,
+        #[serde(default)]
+        y: f32,
+        #[serde(default)]
+        z: f32,
+        of: Box<Sdf>,
+    },
+    Rotate {
+        #[serde(default)]
+        roll: f32,
+        #[serde(default)]
+        pitch: f32,
+        #[serde(default)]
+        yaw: f32,
+        of: Box<Sdf>,
+    },
+    Scale {
+        factor: f32,
+        of: Box<Sdf>,
+    },
+    Union(Vec<Sdf>),
+    Intersection(Vec<Sdf>),
+    /// The first shape with all the others cut out of it
+    Difference(Vec<Sdf>),
+    /// Blends the shapes together. `radius` is roughly how far from their intersection the blend reaches
+    SmoothUnion {
+        radius: f32,
+        of: Vec<Sdf>,
+    },
+    /// Repeats the shape forever with the given period along every axis. 0 doesn't repeat along that axis.
+    /// The shape should fit in one period
+    Repeat {
+        #[serde(default)]
+        x: f32,
+        #[serde(default)]
+        y: f32,
+        #[serde(default)]
+        z: f32,
+        of: Box<Sdf>,
+    },
+    /// Twists the shape around the y axis by `angle` degrees per unit of height
+    Twist {
+        angle: f32,
+        of: Box<Sdf>,
+    },
+    /// Makes the shape thicker by `radius`, rounding its edges
+    Round {
+        radius: f32,
+        of: Box<Sdf>,
+    },
+}
+
+impl Sdf {
+    /// Signed distance from the point to the surface. For twisted shapes it is only an estimate
+    /// that never exceeds the real distance
+    pub fn distance(&self, p: Point3<f32>) -> f32 {
+        match self {
+            Sdf::Sphere(radius) => p.coords.norm() - radius,
+            Sdf::Box { x, y, z } => {
+                let q = p.coords.abs() - Vector3::new(*x, *y, *z);
+                q.map(|c| c.max(0.0)).norm() + q.max().min(0.0)
+            }
+            Sdf::Torus { radius, thickness } => {
+                let ring = Vector3::new(p.x, 0.0, p.z).norm() - radius;
+                (ring * ring + p.y * p.y).sqrt() - thickness
+            }
+            Sdf::Cylinder { radius, height } => {
+                let d = (
+                    Vector3::new(p.x, 0.0, p.z).norm() - radius,
+                    p.y.abs() - height,
+                );
+                d.0.max(d.1).min(0.0) + (d.0.max(0.0).powi(2) + d.1.max(0.0).powi(2)).sqrt()
+            }
+            Sdf::Translate { x, y, z, of } => of.distance(p - Vector3::new(*x, *y, *z)),
+            Sdf::Rotate {
+                roll,
+                pitch,
+                yaw,
+                of,
+            } => {
+                let rotation = na::UnitQuaternion::from_euler_angles(
+                    pitch.to_radians(),
+                    yaw.to_radians(),
+                    roll.to_radians(),
+                );
+                of.distance(rotation.inverse() * p)
+            }
+            Sdf::Scale { factor, of } => of.distance(p / *factor) * factor,
+            Sdf::Union(of) => of
+                .iter()
+                .map(|sdf| sdf.distance(p))
+                .fold(f32::INFINITY, f32::min),
+            Sdf::Intersection(of) => of
+                .iter()
+                .map(|sdf| sdf.distance(p))
+                .fold(f32::NEG_INFINITY, f32::max),
+            Sdf::Difference(of) => {
+                let mut distances = of.iter().map(|sdf| sdf.distance(p));
+                let first = distances.next().unwrap_or(f32::INFINITY);
+                distances.fold(first, |acc, d| acc.max(-d))
+            }
+            Sdf::SmoothUnion { radius, of } => {
+                let mut distances = of.iter().map(|sdf| sdf.distance(p));
+                let first = distances.next().unwrap_or(f32::INFINITY);
+                distances.fold(first, |a, b| smooth_min(a, b, *radius))
+            }
+            Sdf::Repeat { x, y, z, of } => {
+                let wrap = |c: f32, period: f32| {
+                    if period > 0.0 {
+                        c - period * (c / period).round()
+                    } else {
+                        c
+                    }
+                };
+                of.distance(Point3::new(wrap(p.x, *x), wrap(p.y, *y), wrap(p.z, *z)))
+            }
+            Sdf::Twist { angle, of } => {
+                let rate = angle.to_radians();
+                let (sin, cos) = (-rate * p.y).sin_cos();
+                let q = Point3::new(cos * p.x - sin * p.z, p.y, sin * p.x + cos * p.z);
+                // Twisting stretches space by up to this factor at the distance of the point from the axis
+                let stretch = (1.0 + (rate * Vector3::new(p.x, 0.0, p.z).norm()).powi(2)).sqrt();
+                of.distance(q) / stretch
+            }
+            Sdf::Round { radius, of } => of.distance(p) - radius,
+        }
+    }
+
+    /// Points away from the surface
+    pub fn normal(&self, p: Point3<f32>) -> Vector3<f32> {
+        let d = |offset: Vector3<f32>| self.distance(p + offset) - self.distance(p - offset);
+        Vector3::new(
+            d(Vector3::x() * GRADIENT_STEP),
+            d(Vector3::y() * GRADIENT_STEP),
+            d(Vector3::z() * GRADIENT_STEP),
+        )
+        .normalize()
+    }
+}
+
+/// Polynomial smooth minimum
+fn smooth_min(a: f32, b: f32, radius: f32) -> f32 {
+    if radius <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / radius).max(0.0).min(1.0);
+    b + (a - b) * h - radius * h * (1.0 - h)
+}
+
+/// A shape whose surface is where the signed distance function is zero. It is only traced inside
+/// of a cube with the given half size, which is also its bounding box
+#[derive(Clone)]
+pub struct SdfShape {
+    sdf: Sdf,
+    bounds: f32,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Sdf, bounds: f32) -> Self {
+        SdfShape { sdf, bounds }
+    }
+
+    /// Part of the line through `origin` along the unit vector `dir` that lies inside the bounds,
+    /// from no earlier than `start`
+    fn clip(&self, origin: Point3<f32>, dir: Vector3<f32>, start: f32) -> Option<(f32, f32)> {
+        let (mut near, mut far) = (start, f32::INFINITY);
+        for axis in 0..3 {
+            let inverse = dir[axis].recip();
+            let a = (-self.bounds - origin[axis]) * inverse;
+            let b = (self.bounds - origin[axis]) * inverse;
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+        }
+        if near > far {
+            None
+        } else {
+            Some((near, far))
+        }
+    }
+
+    /// Every point where the whole line through the ray crosses the surface, in units of the ray
+    /// direction, with the normal there. The line starts outside, so the crossings alternate between
+    /// entering and leaving, and the last one leaves. Where the bounds cut through the inside of the
+    /// surface, the line crosses the bounds instead
+    pub fn crossings(&self, m: &Isometry3<f32>, ray: &Ray<f32>) -> Vec<(f32, Vector3<f32>)> {
+        let local = ray.inverse_transform_by(m);
+        let length = local.dir.norm();
+        let dir = local.dir / length;
+        let (near, far) = match self.clip(local.origin, dir, f32::NEG_INFINITY) {
+            Some(clipped) => clipped,
+            None => return Vec::new(),
+        };
+
+        let mut crossings = Vec::new();
+        let mut inside = self.sdf.distance(local.origin + dir * near) < 0.0;
+        if inside {
+            crossings.push((near / length, m * -dir));
+        }
+        let mut t = near;
+        // After every crossing the march has to get away from the surface before it can cross again
+        let mut on_surface = true;
+        for _ in 0..MAX_STEPS {
+            let point = local.origin + dir * t;
+            let distance = self.sdf.distance(point) * if inside { -1.0 } else { 1.0 };
+            if distance < HIT_DISTANCE && !on_surface {
+                crossings.push((t / length, m * self.sdf.normal(point)));
+                inside = !inside;
+                on_surface = true;
+                t += 2.0 * HIT_DISTANCE;
+            } else {
+                on_surface &= distance < HIT_DISTANCE;
+                t += distance.abs().max(HIT_DISTANCE);
+            }
+            if t > far {
+                break;
+            }
+        }
+        if inside {
+            crossings.push((far / length, m * dir));
+        }
+        crossings
+    }
+}
+
+impl Shape<f32> for SdfShape {
+    fn aabb(&self, m: &Isometry3<f32>) -> AABB<f32> {
+        let half = Vector3::repeat(self.bounds);
+        AABB::new(Point3::from(-half), Point3::from(half)).transform_by(m)
+    }
+
+    fn tangent_cone_contains_dir(
+        &self,
+        _feature: FeatureId,
+        _m: &Isometry3<f32>,
+        _deformations: Option<&[f32]>,
+        _dir: &Unit<Vector3<f32>>,
+    ) -> bool {
+        false
+    }
+
+    fn as_ray_cast(&self) -> Option<&dyn RayCast<f32>> {
+        Some(self)
+    }
+}
+
+impl RayCast<f32> for SdfShape {
+    fn toi_and_normal_with_ray(
+        &self,
+        m: &Isometry3<f32>,
+        ray: &Ray<f32>,
+        solid: bool,
+    ) -> Option<RayIntersection<f32>> {
+        let local = ray.inverse_transform_by(m);
+        let length = local.dir.norm();
+        let dir = local.dir / length;
+
+        // Only the part of the ray inside the bounds is traced
+        let (near, far) = self.clip(local.origin, dir, 0.0)?;
+
+        let inside = self.sdf.distance(local.origin + dir * near) < 0.0;
+        if inside && near == 0.0 && solid {
+            return Some(RayIntersection::new(
+                0.0,
+                Vector3::zeros(),
+                FeatureId::Unknown,
+            ));
+        }
+
+        // From inside, the ray marches towards the surface on the negative side of the function
+        let sign = if inside { -1.0 } else { 1.0 };
+        let mut t = near;
+        // Rays that start on the surface, like shadow rays, must get away from it before they can hit
+        let mut on_start = true;
+        for _ in 0..MAX_STEPS {
+            let point = local.origin + dir * t;
+            let distance = self.sdf.distance(point) * sign;
+            if distance < 0.0 || (distance < HIT_DISTANCE && !on_start) {
+                let normal = m * self.sdf.normal(point);
+                return Some(RayIntersection::new(t / length, normal, FeatureId::Unknown));
+            }
+
+            on_start &= distance < HIT_DISTANCE;
+            t += distance.max(HIT_DISTANCE);
+            if t > far {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Sdf, SdfShape};
+    use na::{Isometry3, Point3, Vector3};
+    use nc::query::{Ray, RayCast};
+
+    #[test]
+    fn box_distance() {
+        let sdf = Sdf::Box {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        assert_eq!(sdf.distance(Point3::new(3.0, 0.0, 0.0)), 2.0);
+        assert_eq!(sdf.distance(Point3::new(0.0, 0.0, 0.0)), -1.0);
+        assert!((sdf.distance(Point3::new(2.0, 3.0, 0.0)) - 2.0_f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn traces_smooth_union() {
+        let sdf = Sdf::SmoothUnion {
+            radius: 0.5,
+            of: vec![
+                Sdf::Translate {
+                    x: -1.0,
+                    y: 0.0,
+                    z: 0.0,
+                    of: Box::new(Sdf::Sphere(1.0)),
+                },
+                Sdf::Translate {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                    of: Box::new(Sdf::Sphere(1.0)),
+                },
+            ],
+        };
+        let shape = SdfShape::new(sdf, 3.0);
+        let m = Isometry3::translation(0.0, 0.0, 5.0);
+
+        // The blend fills the gap where the spheres touch, so the ray hits in front of the touching point
+        let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), Vector3::z() * 2.0);
+        let hit = shape.toi_and_normal_with_ray(&m, &ray, true).unwrap();
+        assert!(hit.toi * 2.0 < 5.0 - 0.1);
+        assert!((hit.normal - -Vector3::z()).norm() < 1e-2);
+
+        let miss = Ray::new(Point3::new(0.0, 2.5, 0.0), Vector3::z());
+        assert!(shape.toi_and_normal_with_ray(&m, &miss, true).is_none());
+    }
+}
@@ -0,0 +1,750 @@
+//! Shapes that ncollide doesn't have. They only support what the tracer needs: bounding boxes and ray casts
+
+use crate::sdf::SdfShape;
+use na::{DMatrix, Isometry3, Matrix3, Point2, Point3, Unit, Vector3};
+use nc::{
+    bounding_volume::{BoundingVolume, AABB},
+    query::{Ray, RayCast, RayIntersection},
+    shape::{FeatureId, HeightField, Shape, ShapeHandle},
+};
+
+/// A shape scaled, sheared or mirrored by a linear map in its local space
+#[derive(Clone)]
+pub struct Transformed {
+    shape: ShapeHandle<f32>,
+    linear: Matrix3<f32>,
+    inverse: Matrix3<f32>,
+}
+
+impl Transformed {
+    /// Returns `None` if the map is not invertible, for example when it scales an axis by zero
+    pub fn new(shape: ShapeHandle<f32>, linear: Matrix3<f32>) -> Option<Self> {
+        let inverse = linear.try_inverse()?;
+        Some(Transformed {
+            shape,
+            linear,
+            inverse,
+        })
+    }
+}
+
+impl Shape<f32> for Transformed {
+    fn aabb(&self, m: &Isometry3<f32>) -> AABB<f32> {
+        let inner = self.shape.local_aabb();
+        let center = self.linear * inner.center().coords;
+        let half_extents = self.linear.abs() * inner.half_extents();
+
+        AABB::new(
+            Point3::from(center - half_extents),
+            Point3::from(center + half_extents),
+        )
+        .transform_by(m)
+    }
+
+    fn tangent_cone_contains_dir(
+        &self,
+        _feature: FeatureId,
+        _m: &Isometry3<f32>,
+        _deformations: Option<&[f32]>,
+        _dir: &Unit<Vector3<f32>>,
+    ) -> bool {
+        false
+    }
+
+    fn as_ray_cast(&self) -> Option<&dyn RayCast<f32>> {
+        Some(self)
+    }
+}
+
+impl Transformed {
+    fn spans(&self, m: &Isometry3<f32>, ray: &Ray<f32>) -> Vec<Span> {
+        let local = ray.inverse_transform_by(m);
+        let inner = Ray::new(
+            Point3::from(self.inverse * local.origin.coords),
+            self.inverse * local.dir,
+        );
+
+        let normal = |n: Vector3<f32>| m * (self.inverse.transpose() * n).normalize();
+        spans(&*self.shape, &Isometry3::identity(), &inner)
+            .into_iter()
+            .map(|span| Span {
+                enter_normal: normal(span.enter_normal),
+                exit_normal: normal(span.exit_normal),
+                ..span
+            })
+            .collect()
+    }
+}
+
+impl RayCast<f32> for Transformed {
+    fn toi_and_normal_with_ray(
+        &self,
+        m: &Isometry3<f32>,
+        ray: &Ray<f32>,
+        solid: bool,
+    ) -> Option<RayIntersection<f32>> {
+        // The map is linear, so the time of impact is the same in both spaces
+        let local = ray.inverse_transform_by(m);
+        let inner = Ray::new(
+            Point3::from(self.inverse * local.origin.coords),
+            self.inverse * local.dir,
+        );
+
+        self.shape
+            .as_ray_cast()?
+            .toi_and_normal_with_ray(&Isometry3::identity(), &inner, solid)
+            .map(|mut intersection| {
+                let normal = self.inverse.transpose() * intersection.normal;
+                intersection.normal = m * normal.normalize();
+                intersection
+            })
+    }
+}
+
+/// Part of a ray that lies inside a shape, from `enter` to `exit` in units of the ray direction.
+/// The normals point out of the shape
+#[derive(Clone, Copy, Debug)]
+struct Span {
+    enter: f32,
+    enter_normal: Vector3<f32>,
+    exit: f32,
+    exit_normal: Vector3<f32>,
+}
+
+impl Span {
+    /// The same part of the ray as the inside of a hole, whose normals point the other way
+    fn inverted(self) -> Span {
+        Span {
+            enter_normal: -self.enter_normal,
+            exit_normal: -self.exit_normal,
+            ..self
+        }
+    }
+}
+
+/// Parts of the whole line through the ray that lie inside the shape, sorted and not overlapping
+fn spans(shape: &dyn Shape<f32>, m: &Isometry3<f32>, ray: &Ray<f32>) -> Vec<Span> {
+    if let Some(csg) = shape.as_shape::<Csg>() {
+        return csg.spans(m, ray);
+    }
+    if let Some(transformed) = shape.as_shape::<Transformed>() {
+        return transformed.spans(m, ray);
+    }
+    if let Some(blobs) = shape.as_shape::<Blobs>() {
+        return blobs.spans(m, ray);
+    }
+    if let Some(sdf) = shape.as_shape::<SdfShape>() {
+        return sdf
+            .crossings(m, ray)
+            .chunks(2)
+            .map(|pair| Span {
+                enter: pair[0].0,
+                enter_normal: pair[0].1,
+                exit: pair[1].0,
+                exit_normal: pair[1].1,
+            })
+            .collect();
+    }
+
+    // Any other shape is assumed to be convex, so the line enters and leaves it at most once.
+    // Both points are found by casting from outside of the bounding sphere
+    let ray_cast = match shape.as_ray_cast() {
+        Some(ray_cast) => ray_cast,
+        None => return Vec::new(),
+    };
+    let sphere = shape.bounding_sphere(m);
+    let length = ray.dir.norm();
+    let middle = (sphere.center() - ray.origin).dot(&ray.dir) / (length * length);
+    let half = sphere.radius() / length * 1.01 + 1e-3;
+
+    let before = Ray::new(ray.point_at(middle - half), ray.dir);
+    let after = Ray::new(ray.point_at(middle + half), -ray.dir);
+    match (
+        ray_cast.toi_and_normal_with_ray(m, &before, true),
+        ray_cast.toi_and_normal_with_ray(m, &after, true),
+    ) {
+        (Some(enter), Some(exit)) => vec![Span {
+            enter: middle - half + enter.toi,
+            enter_normal: enter.normal,
+            exit: middle + half - exit.toi,
+            exit_normal: exit.normal,
+        }],
+        _ => Vec::new(),
+    }
+}
+
+/// Parts of the line inside any of the spans
+fn union(mut spans: Vec<Span>) -> Vec<Span> {
+    spans.sort_by(|a, b| {
+        a.enter
+            .partial_cmp(&b.enter)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut result: Vec<Span> = Vec::with_capacity(spans.len());
+    for span in spans {
+        match result.last_mut() {
+            Some(last) if span.enter <= last.exit => {
+                if span.exit > last.exit {
+                    last.exit = span.exit;
+                    last.exit_normal = span.exit_normal;
+                }
+            }
+            _ => result.push(span),
+        }
+    }
+    result
+}
+
+/// Parts of the line inside both lists of spans
+fn intersection(a: &[Span], b: &[Span]) -> Vec<Span> {
+    let mut result = Vec::new();
+    for a in a {
+        for b in b {
+            let (enter, enter_normal) = if a.enter > b.enter {
+                (a.enter, a.enter_normal)
+            } else {
+                (b.enter, b.enter_normal)
+            };
+            let (exit, exit_normal) = if a.exit < b.exit {
+                (a.exit, a.exit_normal)
+            } else {
+                (b.exit, b.exit_normal)
+            };
+            if enter < exit {
+                result.push(Span {
+                    enter,
+                    enter_normal,
+                    exit,
+                    exit_normal,
+                });
+            }
+        }
+    }
+    union(result)
+}
+
+/// Parts of the line inside `a` but not inside `b`
+fn difference(a: &[Span], b: &[Span]) -> Vec<Span> {
+    // `b` is sorted, so its complement within a span of `a` is the gaps between its spans
+    let mut result = Vec::new();
+    for &a in a {
+        let mut rest = Some(a);
+        for b in b.iter().map(|b| b.inverted()) {
+            let current = match rest {
+                Some(current) => current,
+                None => break,
+            };
+            if b.exit <= current.enter || b.enter >= current.exit {
+                continue;
+            }
+
+            if b.enter > current.enter {
+                result.push(Span {
+                    exit: b.enter,
+                    exit_normal: b.enter_normal,
+                    ..current
+                });
+            }
+            rest = if b.exit < current.exit {
+                Some(Span {
+                    enter: b.exit,
+                    enter_normal: b.exit_normal,
+                    ..current
+                })
+            } else {
+                None
+            };
+        }
+        result.extend(rest);
+    }
+    result
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    /// The first part with all the others cut out of it
+    Difference,
+}
+
+/// Constructive solid geometry: parts combined into one solid. Parts can be any shapes,
+/// as long as the ones that aren't `Csg`, `Transformed`, `Blobs` or `SdfShape` are convex
+#[derive(Clone)]
+pub struct Csg {
+    operation: CsgOperation,
+    parts: Vec<(Isometry3<f32>, ShapeHandle<f32>)>,
+}
+
+impl Csg {
+    /// Positions of the parts are relative to the position of the whole shape
+    pub fn new(operation: CsgOperation, parts: Vec<(Isometry3<f32>, ShapeHandle<f32>)>) -> Self {
+        Csg { operation, parts }
+    }
+
+    fn spans(&self, m: &Isometry3<f32>, ray: &Ray<f32>) -> Vec<Span> {
+        let mut parts = self
+            .parts
+            .iter()
+            .map(|(pos, shape)| spans(&**shape, &(m * pos), ray));
+
+        let first = match parts.next() {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+        match self.operation {
+            CsgOperation::Union => union(parts.flatten().chain(first).collect()),
+            CsgOperation::Intersection => parts.fold(first, |acc, part| intersection(&acc, &part)),
+            CsgOperation::Difference => difference(&first, &union(parts.flatten().collect())),
+        }
+    }
+}
+
+impl Shape<f32> for Csg {
+    fn aabb(&self, m: &Isometry3<f32>) -> AABB<f32> {
+        let mut aabbs = self.parts.iter().map(|(pos, shape)| shape.aabb(&(m * pos)));
+        let first = match aabbs.next() {
+            Some(first) => first,
+            None => {
+                return AABB::new(
+                    Point3::from(m.translation.vector),
+                    Point3::from(m.translation.vector),
+                )
+            }
+        };
+
+        match self.operation {
+            CsgOperation::Union => aabbs.fold(first, |acc, aabb| acc.merged(&aabb)),
+            CsgOperation::Intersection => aabbs.fold(first, |acc, aabb| {
+                let mins = acc.mins().coords.zip_map(&aabb.mins().coords, f32::max);
+                let maxs = acc
+                    .maxs()
+                    .coords
+                    .zip_map(&aabb.maxs().coords, f32::min)
+                    .zip_map(&mins, f32::max);
+                AABB::new(Point3::from(mins), Point3::from(maxs))
+            }),
+            CsgOperation::Difference => first,
+        }
+    }
+
+    fn tangent_cone_contains_dir(
+        &self,
+        _feature: FeatureId,
+        _m: &Isometry3<f32>,
+        _deformations: Option<&[f32]>,
+        _dir: &Unit<Vector3<f32>>,
+    ) -> bool {
+        false
+    }
+
+    fn as_ray_cast(&self) -> Option<&dyn RayCast<f32>> {
+        Some(self)
+    }
+}
+
+impl RayCast<f32> for Csg {
+    fn toi_and_normal_with_ray(
+        &self,
+        m: &Isometry3<f32>,
+        ray: &Ray<f32>,
+        solid: bool,
+    ) -> Option<RayIntersection<f32>> {
+        first_intersection(self.spans(m, ray), solid)
+    }
+}
+
+/// Where a ray hits the first of the spans of its line that isn't behind it
+fn first_intersection(spans: Vec<Span>, solid: bool) -> Option<RayIntersection<f32>> {
+    let span = spans.into_iter().find(|span| span.exit >= 0.0)?;
+
+    if span.enter >= 0.0 {
+        Some(RayIntersection::new(
+            span.enter,
+            span.enter_normal,
+            FeatureId::Unknown,
+        ))
+    } else if solid {
+        // Like ncollide's shapes, a solid shape is hit right away by rays starting inside it
+        Some(RayIntersection::new(
+            0.0,
+            Vector3::zeros(),
+            FeatureId::Unknown,
+        ))
+    } else {
+        Some(RayIntersection::new(
+            span.exit,
+            span.exit_normal,
+            FeatureId::Unknown,
+        ))
+    }
+}
+
+/// Metaballs: every blob adds a field that falls off smoothly from 1 at its center to 0 at its radius,
+/// and the surface is where the sum of the fields reaches the threshold. Nearby blobs melt together.
+/// A lone blob is a ball whose radius is `radius * sqrt(1 - threshold^(1/3))`
+#[derive(Clone)]
+pub struct Blobs {
+    blobs: Vec<(Point3<f32>, f32)>,
+    threshold: f32,
+}
+
+impl Blobs {
+    /// Centers are relative to the position of the whole shape
+    pub fn new(blobs: Vec<(Point3<f32>, f32)>, threshold: f32) -> Self {
+        Blobs { blobs, threshold }
+    }
+
+    fn field(&self, p: Point3<f32>) -> f32 {
+        self.blobs
+            .iter()
+            .map(|(center, radius)| {
+                let falloff = 1.0 - (p - center).norm_squared() / (radius * radius);
+                falloff.max(0.0).powi(3)
+            })
+            .sum()
+    }
+
+    /// Points out of the surface, against the gradient of the field
+    fn normal(&self, p: Point3<f32>) -> Vector3<f32> {
+        let gradient: Vector3<f32> = self
+            .blobs
+            .iter()
+            .map(|(center, radius)| {
+                let r2 = radius * radius;
+                let falloff = (1.0 - (p - center).norm_squared() / r2).max(0.0);
+                (p - center) * (-6.0 * falloff * falloff / r2)
+            })
+            .sum();
+        -gradient.normalize()
+    }
+
+    fn spans(&self, m: &Isometry3<f32>, ray: &Ray<f32>) -> Vec<Span> {
+        let local = ray.inverse_transform_by(m);
+        let length2 = local.dir.norm_squared();
+
+        // The field is zero outside of the reach of the blobs, so only these parts of the line are searched
+        let reach = union(
+            self.blobs
+                .iter()
+                .filter_map(|(center, radius)| {
+                    let middle = (center - local.origin).dot(&local.dir) / length2;
+                    let closest2 = (local.point_at(middle) - center).norm_squared();
+                    let half = ((radius * radius - closest2) / length2).sqrt();
+                    if half.is_nan() {
+                        return None;
+                    }
+                    Some(Span {
+                        enter: middle - half,
+                        enter_normal: Vector3::zeros(),
+                        exit: middle + half,
+                        exit_normal: Vector3::zeros(),
+                    })
+                })
+                .collect(),
+        );
+
+        // Steps small enough not to jump over the surface of the smallest blob
+        let smallest = self
+            .blobs
+            .iter()
+            .map(|&(_, radius)| radius)
+            .filter(|&radius| radius > 0.0)
+            .fold(f32::INFINITY, f32::min);
+        let step = smallest * BLOB_STEP / length2.sqrt();
+
+        let inside = |t: f32| self.field(local.point_at(t)) >= self.threshold;
+        let root = |mut outside: f32, mut inner: f32| {
+            for _ in 0..32 {
+                let middle = (outside + inner) / 2.0;
+                if inside(middle) {
+                    inner = middle;
+                } else {
+                    outside = middle;
+                }
+            }
+            (outside + inner) / 2.0
+        };
+        let normal = |t: f32| m * self.normal(local.point_at(t));
+
+        let mut result = Vec::new();
+        for part in reach {
+            // The field is zero at both ends of the part, so the line starts and ends outside
+            let mut enter = None;
+            let mut t = part.enter;
+            while t < part.exit {
+                let next = (t + step).min(part.exit);
+                match (enter, inside(next)) {
+                    (None, true) => enter = Some(root(t, next)),
+                    (Some(start), false) => {
+                        let exit = root(next, t);
+                        result.push(Span {
+                            enter: start,
+                            enter_normal: normal(start),
+                            exit,
+                            exit_normal: normal(exit),
+                        });
+                        enter = None;
+                    }
+                    _ => {}
+                }
+                t = next;
+            }
+        }
+        result
+    }
+}
+
+/// Step of the search for the surface of blobs, relative to the radius of the smallest blob
+const BLOB_STEP: f32 = 0.05;
+
+impl Shape<f32> for Blobs {
+    fn aabb(&self, m: &Isometry3<f32>) -> AABB<f32> {
+        let mut aabbs = self.blobs.iter().map(|&(center, radius)| {
+            let half = Vector3::repeat(radius);
+            AABB::new(center - half, center + half)
+        });
+        let first = match aabbs.next() {
+            Some(first) => first,
+            None => AABB::new(Point3::origin(), Point3::origin()),
+        };
+        aabbs
+            .fold(first, |acc, aabb| acc.merged(&aabb))
+            .transform_by(m)
+    }
+
+    fn tangent_cone_contains_dir(
+        &self,
+        _feature: FeatureId,
+        _m: &Isometry3<f32>,
+        _deformations: Option<&[f32]>,
+        _dir: &Unit<Vector3<f32>>,
+    ) -> bool {
+        false
+    }
+
+    fn as_ray_cast(&self) -> Option<&dyn RayCast<f32>> {
+        Some(self)
+    }
+}
+
+impl RayCast<f32> for Blobs {
+    fn toi_and_normal_with_ray(
+        &self,
+        m: &Isometry3<f32>,
+        ray: &Ray<f32>,
+        solid: bool,
+    ) -> Option<RayIntersection<f32>> {
+        first_intersection(self.spans(m, ray), solid)
+    }
+}
+
+/// Terrain made of a grid of heights, centered on its position. Hits have UVs that go from 0 to 1
+/// across the grid, with u along the columns and v along the rows
+#[derive(Clone)]
+pub struct Heightfield {
+    inner: HeightField<f32>,
+}
+
+impl Heightfield {
+    /// Rows of the heights go along z and columns along x. `scale` is the size of the whole grid,
+    /// and the heights are multiplied by its y component. There must be at least 2 rows and columns
+    pub fn new(heights: DMatrix<f32>, scale: Vector3<f32>) -> Self {
+        Heightfield {
+            inner: HeightField::new(heights, scale),
+        }
+    }
+}
+
+impl Shape<f32> for Heightfield {
+    fn aabb(&self, m: &Isometry3<f32>) -> AABB<f32> {
+        Shape::aabb(&self.inner, m)
+    }
+
+    fn tangent_cone_contains_dir(
+        &self,
+        _feature: FeatureId,
+        _m: &Isometry3<f32>,
+        _deformations: Option<&[f32]>,
+        _dir: &Unit<Vector3<f32>>,
+    ) -> bool {
+        false
+    }
+
+    fn as_ray_cast(&self) -> Option<&dyn RayCast<f32>> {
+        Some(self)
+    }
+}
+
+impl RayCast<f32> for Heightfield {
+    fn toi_and_normal_with_ray(
+        &self,
+        m: &Isometry3<f32>,
+        ray: &Ray<f32>,
+        solid: bool,
+    ) -> Option<RayIntersection<f32>> {
+        let mut intersection = self.inner.toi_and_normal_with_ray(m, ray, solid)?;
+        let point = ray.inverse_transform_by(m).point_at(intersection.toi);
+        let scale = self.inner.scale();
+        intersection.uvs = Some(Point2::new(
+            point.x / scale.x + 0.5,
+            point.z / scale.z + 0.5,
+        ));
+        Some(intersection)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Blobs, Csg, CsgOperation, Heightfield, Transformed};
+    use crate::sdf::{Sdf, SdfShape};
+    use na::{DMatrix, Isometry3, Matrix3, Point3, Vector3};
+    use nc::{
+        query::{Ray, RayCast},
+        shape::{Ball, Cuboid, ShapeHandle},
+    };
+
+    #[test]
+    fn stretched_ball() {
+        let ball = ShapeHandle::new(Ball::new(1.0));
+        let ellipsoid =
+            Transformed::new(ball, Matrix3::from_diagonal(&Vector3::new(2.0, 1.0, 1.0))).unwrap();
+        let m = Isometry3::translation(0.0, 0.0, 5.0);
+
+        let along_x = Ray::new(Point3::new(-5.0, 0.0, 5.0), Vector3::x());
+        let hit = ellipsoid
+            .toi_and_normal_with_ray(&m, &along_x, true)
+            .unwrap();
+        assert!((hit.toi - 3.0).abs() < 1e-5);
+
+        // On the slanted side the normal is not the direction from the center
+        let diagonal = Point3::new(2.0_f32.sqrt(), 0.5_f32.sqrt(), 0.0);
+        let towards = Ray::new(diagonal + Vector3::new(0.0, 1.0, 5.0), -Vector3::y());
+        let hit = ellipsoid
+            .toi_and_normal_with_ray(&m, &towards, true)
+            .unwrap();
+        let expected = Vector3::new(diagonal.x / 4.0, diagonal.y, 0.0).normalize();
+        assert!((hit.normal - expected).norm() < 1e-4);
+    }
+
+    #[test]
+    fn cube_with_spherical_hole() {
+        let cube = ShapeHandle::new(Cuboid::new(Vector3::new(1.0, 1.0, 1.0)));
+        let ball = ShapeHandle::new(Ball::new(0.5));
+        let csg = Csg::new(
+            CsgOperation::Difference,
+            vec![
+                (Isometry3::identity(), cube),
+                (Isometry3::translation(0.0, 0.0, -1.0), ball),
+            ],
+        );
+        let m = Isometry3::translation(0.0, 0.0, 5.0);
+
+        // Through the hole, the ray hits the far side of the ball, which faces the ray
+        let center = Ray::new(Point3::origin(), Vector3::z());
+        let hit = csg.toi_and_normal_with_ray(&m, &center, true).unwrap();
+        assert!((hit.toi - 4.5).abs() < 1e-4);
+        assert!((hit.normal + Vector3::z()).norm() < 1e-4);
+
+        // Next to the hole, the ray hits the face of the cube
+        let side = Ray::new(Point3::new(0.8, 0.0, 0.0), Vector3::z());
+        let hit = csg.toi_and_normal_with_ray(&m, &side, true).unwrap();
+        assert!((hit.toi - 4.0).abs() < 1e-4);
+        assert!((hit.normal + Vector3::z()).norm() < 1e-4);
+    }
+
+    #[test]
+    fn concave_sdf_part() {
+        let torus = SdfShape::new(
+            Sdf::Torus {
+                radius: 2.0,
+                thickness: 0.5,
+            },
+            2.5,
+        );
+        let cube = ShapeHandle::new(Cuboid::new(Vector3::new(2.0, 2.0, 2.0)));
+        let csg = Csg::new(
+            CsgOperation::Intersection,
+            vec![
+                (Isometry3::identity(), ShapeHandle::new(torus)),
+                (Isometry3::identity(), cube),
+            ],
+        );
+
+        // From inside the hole, the ray crosses it before it reaches the ring
+        let from_hole = Ray::new(Point3::new(-1.0, 0.0, 0.0), Vector3::x());
+        let hit = csg
+            .toi_and_normal_with_ray(&Isometry3::identity(), &from_hole, true)
+            .unwrap();
+        assert!((hit.toi - 2.5).abs() < 1e-3);
+        assert!((hit.normal + Vector3::x()).norm() < 1e-3);
+
+        let through_hole = Ray::new(Point3::new(0.0, 5.0, 0.0), -Vector3::y());
+        assert!(csg
+            .toi_and_normal_with_ray(&Isometry3::identity(), &through_hole, true)
+            .is_none());
+    }
+
+    #[test]
+    fn intersection_of_balls() {
+        let ball = ShapeHandle::new(Ball::new(1.0));
+        let lens = Csg::new(
+            CsgOperation::Intersection,
+            vec![
+                (Isometry3::translation(-0.5, 0.0, 0.0), ball.clone()),
+                (Isometry3::translation(0.5, 0.0, 0.0), ball),
+            ],
+        );
+
+        let along_x = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vector3::x());
+        let hit = lens
+            .toi_and_normal_with_ray(&Isometry3::identity(), &along_x, true)
+            .unwrap();
+        assert!((hit.toi - 4.5).abs() < 1e-4);
+
+        let missing = Ray::new(Point3::new(-5.0, 0.95, 0.0), Vector3::x());
+        assert!(lens
+            .toi_and_normal_with_ray(&Isometry3::identity(), &missing, true)
+            .is_none());
+    }
+
+    #[test]
+    fn blobs_melt_together() {
+        let lone = Blobs::new(vec![(Point3::origin(), 1.0)], 0.125);
+        let m = Isometry3::translation(0.0, 0.0, 5.0);
+        let ray = Ray::new(Point3::origin(), Vector3::z());
+
+        // 1 - d^2 = 0.5, so the lone blob is a ball with a radius of sqrt(0.5)
+        let hit = lone.toi_and_normal_with_ray(&m, &ray, true).unwrap();
+        assert!((hit.toi - (5.0 - 0.5_f32.sqrt())).abs() < 1e-4);
+        assert!((hit.normal + Vector3::z()).norm() < 1e-4);
+
+        // Between two blobs, where neither reaches the threshold alone, their fields add up
+        let pair = Blobs::new(
+            vec![
+                (Point3::new(-0.75, 0.0, 0.0), 1.0),
+                (Point3::new(0.75, 0.0, 0.0), 1.0),
+            ],
+            0.125,
+        );
+        assert!(pair.toi_and_normal_with_ray(&m, &ray, true).is_some());
+        let half = Blobs::new(vec![(Point3::new(0.75, 0.0, 0.0), 1.0)], 0.125);
+        assert!(half.toi_and_normal_with_ray(&m, &ray, true).is_none());
+    }
+
+    #[test]
+    fn heightfield_uvs() {
+        // A ramp that rises along x from 0 to 1
+        let heights = DMatrix::from_fn(3, 3, |_, col| col as f32 / 2.0);
+        let terrain = Heightfield::new(heights, Vector3::new(4.0, 2.0, 4.0));
+
+        let down = Ray::new(Point3::new(1.0, 5.0, -1.0), -Vector3::y());
+        let hit = terrain
+            .toi_and_normal_with_ray(&Isometry3::identity(), &down, true)
+            .unwrap();
+        assert!((hit.toi - 3.5).abs() < 1e-4);
+        let uvs = hit.uvs.unwrap();
+        assert!((uvs.x - 0.75).abs() < 1e-5 && (uvs.y - 0.25).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,195 @@
+//! Splitting of the image into tiles and the orders in which they are traced
+
+use serde::{Deserialize, Serialize};
+
+/// A rectangular part of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tile {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Tile {
+    pub fn pixels(&self) -> impl Iterator<Item = (u16, u16)> {
+        let Tile {
+            x,
+            y,
+            width,
+            height,
+        } = *self;
+        (y..y + height).flat_map(move |y| (x..x + width).map(move |x| (x, y)))
+    }
+
+    pub fn area(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    /// Row by row, from the top left corner
+    Scanline,
+    /// Outwards from the middle of the image
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles close to each other
+    Hilbert,
+}
+
+impl Default for TileOrder {
+    fn default() -> Self {
+        TileOrder::Scanline
+    }
+}
+
+/// Covers an image of the given size with tiles. Tiles at the right and bottom edges are cut
+/// to fit in the image
+pub fn tiles(size: (u16, u16), tile_size: u16, order: TileOrder) -> Vec<Tile> {
+    let region = Tile {
+        x: 0,
+        y: 0,
+        width: size.0,
+        height: size.1,
+    };
+    tiles_in(region, tile_size, order)
+}
+
+/// Like `tiles`, but only covers the given region of the image
+pub fn tiles_in(region: Tile, tile_size: u16, order: TileOrder) -> Vec<Tile> {
+    let size = (region.width, region.height);
+    let tile_size = tile_size.max(1);
+    let columns = (size.0 as u32 + tile_size as u32 - 1) / tile_size as u32;
+    let rows = (size.1 as u32 + tile_size as u32 - 1) / tile_size as u32;
+
+    let to_tile = |(column, row): (u32, u32)| {
+        let x = (column * tile_size as u32) as u16;
+        let y = (row * tile_size as u32) as u16;
+        Tile {
+            x: region.x + x,
+            y: region.y + y,
+            width: tile_size.min(size.0 - x),
+            height: tile_size.min(size.1 - y),
+        }
+    };
+
+    let cells: Vec<(u32, u32)> = match order {
+        TileOrder::Scanline => (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .collect(),
+        TileOrder::Spiral => spiral(columns, rows),
+        TileOrder::Hilbert => hilbert(columns, rows),
+    };
+
+    cells.into_iter().map(to_tile).collect()
+}
+
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let total = (columns * rows) as usize;
+    let mut cells = Vec::with_capacity(total);
+
+    let (mut x, mut y) = ((columns / 2) as i64, (rows / 2) as i64);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut leg = 1;
+    let mut direction = 0;
+
+    let visit = |x: i64, y: i64, cells: &mut Vec<(u32, u32)>| {
+        if x >= 0 && y >= 0 && x < columns as i64 && y < rows as i64 {
+            cells.push((x as u32, y as u32));
+        }
+    };
+
+    visit(x, y, &mut cells);
+    while cells.len() < total {
+        // Every leg length is walked twice: right and down, then left and up
+        for _ in 0..2 {
+            let (dx, dy) = directions[direction];
+            for _ in 0..leg {
+                x += dx;
+                y += dy;
+                visit(x, y, &mut cells);
+            }
+            direction = (direction + 1) % 4;
+        }
+        leg += 1;
+    }
+
+    cells
+}
+
+fn hilbert(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let side = columns.max(rows).next_power_of_two();
+
+    (0..side * side)
+        .map(|d| hilbert_point(side, d))
+        .filter(|&(x, y)| x < columns && y < rows)
+        .collect()
+}
+
+/// Converts a distance along the Hilbert curve filling a `side`x`side` square into coordinates
+fn hilbert_point(side: u32, mut d: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (d / 2);
+        let ry = 1 & (d ^ rx);
+
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        d /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tiles, TileOrder};
+
+    fn covers_image(order: TileOrder) {
+        let size = (70, 45);
+        let tiles = tiles(size, 16, order);
+        assert_eq!(tiles.len(), 5 * 3);
+
+        let mut covered = vec![0; size.0 as usize * size.1 as usize];
+        for (x, y) in tiles.iter().flat_map(|tile| tile.pixels()) {
+            covered[y as usize * size.0 as usize + x as usize] += 1;
+        }
+        assert!(covered.into_iter().all(|n| n == 1));
+    }
+
+    #[test]
+    fn scanline_covers_image() {
+        covers_image(TileOrder::Scanline);
+    }
+
+    #[test]
+    fn spiral_covers_image() {
+        covers_image(TileOrder::Spiral);
+    }
+
+    #[test]
+    fn hilbert_covers_image() {
+        covers_image(TileOrder::Hilbert);
+    }
+
+    #[test]
+    fn hilbert_tiles_are_adjacent() {
+        let tiles = tiles((64, 64), 16, TileOrder::Hilbert);
+        for pair in tiles.windows(2) {
+            let dx = (pair[0].x as i32 - pair[1].x as i32).abs();
+            let dy = (pair[0].y as i32 - pair[1].y as i32).abs();
+            assert_eq!(dx + dy, 16);
+        }
+    }
+}